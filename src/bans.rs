@@ -0,0 +1,196 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::mask;
+
+#[cfg(test)]
+#[path = "./bans_test.rs"]
+mod bans_test;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanKind {
+    // user@host ban checked at registration.
+    K,
+    // IP or CIDR ban checked as soon as the connection is accepted.
+    D,
+    // Network-wide user@host ban. There is no server linking yet, so on
+    // this server it behaves exactly like a K-line.
+    G,
+}
+
+impl BanKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BanKind::K => "K-Line",
+            BanKind::D => "D-Line",
+            BanKind::G => "G-Line",
+        }
+    }
+
+    pub fn privilege(&self) -> &'static str {
+        match self {
+            BanKind::K => "kline",
+            BanKind::D => "dline",
+            BanKind::G => "gline",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Ban {
+    pub kind: BanKind,
+    pub mask: String,
+    pub reason: String,
+    pub set_by: String,
+    // Seconds since the unix epoch; `None` is a permanent ban.
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    pub fn new(kind: BanKind, mask: &str, reason: &str, set_by: &str, duration: Option<Duration>) -> Self {
+        Ban {
+            kind,
+            mask: mask.into(),
+            reason: reason.into(),
+            set_by: set_by.into(),
+            expires: duration.map(|d| unix_time(SystemTime::now() + d)),
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= unix_time(now))
+    }
+
+    // D-lines only look at the address, K- and G-lines match `user@host`
+    // against both the hostname and the address.
+    pub fn matches(&self, user: &str, host: &str, ip: Option<IpAddr>) -> bool {
+        match self.kind {
+            BanKind::D => ip.is_some_and(|ip| ip_matches(&self.mask, ip)),
+            BanKind::K | BanKind::G => {
+                let Some((user_mask, host_mask)) = self.mask.split_once('@') else {
+                    return false;
+                };
+                if !mask::matches(user_mask, user) {
+                    return false;
+                }
+
+                mask::matches(host_mask, host) || ip.is_some_and(|ip| ip_matches(host_mask, ip))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BanList {
+    #[serde(default)]
+    bans: Vec<Ban>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    // A missing file is not an error, it is created on the first save.
+    pub fn load(path: &Path) -> Result<BanList> {
+        let mut bans = if path.exists() {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("Cannot read {}", path.display()))?;
+            toml::from_str(&contents)
+                .with_context(|| format!("Cannot parse {}", path.display()))?
+        } else {
+            BanList::new()
+        };
+        bans.path = Some(path.into());
+
+        Ok(bans)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let contents = toml::to_string(self)?;
+        fs::write(path, contents).with_context(|| format!("Cannot write {}", path.display()))
+    }
+
+    // Replaces a ban of the same kind and mask, if any.
+    pub fn add(&mut self, ban: Ban) -> Result<()> {
+        self.bans.retain(|b| b.kind != ban.kind || b.mask != ban.mask);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    pub fn remove(&mut self, kind: BanKind, mask: &str) -> Result<bool> {
+        let len = self.bans.len();
+        self.bans.retain(|b| b.kind != kind || b.mask != mask);
+
+        if self.bans.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn expire(&mut self, now: SystemTime) -> Result<()> {
+        let len = self.bans.len();
+        self.bans.retain(|b| !b.is_expired(now));
+
+        if self.bans.len() != len {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn find_dline(&mut self, ip: IpAddr) -> Option<Ban> {
+        let _ = self.expire(SystemTime::now());
+        self.bans
+            .iter()
+            .find(|b| b.kind == BanKind::D && b.matches("", "", Some(ip)))
+            .cloned()
+    }
+
+    pub fn find_kline(&mut self, user: &str, host: &str, ip: Option<IpAddr>) -> Option<Ban> {
+        let _ = self.expire(SystemTime::now());
+        self.bans
+            .iter()
+            .find(|b| b.kind != BanKind::D && b.matches(user, host, ip))
+            .cloned()
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// Accepts either a CIDR block such as `10.0.0.0/8` or `2001:db8::/32`, or a
+// glob mask matched against the textual address.
+pub fn ip_matches(mask: &str, ip: IpAddr) -> bool {
+    let Some((network, prefix)) = mask.split_once('/') else {
+        return mask::matches(mask, &ip.to_string());
+    };
+    let (Ok(network), Ok(prefix)) = (network.parse::<IpAddr>(), prefix.parse::<u32>()) else {
+        return false;
+    };
+
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) if prefix <= 32 => {
+            let bits = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & bits == u32::from(ip) & bits
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) if prefix <= 128 => {
+            let bits = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & bits == u128::from(ip) & bits
+        }
+        _ => false,
+    }
+}
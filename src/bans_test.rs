@@ -0,0 +1,82 @@
+use super::*;
+
+#[test]
+fn test_ip_matches() {
+    let ip = "192.168.1.20".parse().unwrap();
+    assert!(ip_matches("192.168.1.20", ip));
+    assert!(ip_matches("192.168.*", ip));
+    assert!(ip_matches("192.168.0.0/16", ip));
+    assert!(ip_matches("0.0.0.0/0", ip));
+    assert!(!ip_matches("192.168.2.0/24", ip));
+    assert!(!ip_matches("2001:db8::/32", ip));
+    assert!(!ip_matches("192.168.0.0/40", ip));
+
+    let ip = "2001:db8::1".parse().unwrap();
+    assert!(ip_matches("2001:db8::/32", ip));
+    assert!(!ip_matches("2001:db9::/32", ip));
+}
+
+#[test]
+fn test_ban_matches() {
+    let ip = Some("10.0.0.5".parse().unwrap());
+
+    let kline = Ban::new(BanKind::K, "*@*.example.com", "spam", "alice", None);
+    assert!(kline.matches("bob", "host.example.com", ip));
+    assert!(!kline.matches("bob", "example.org", ip));
+
+    let kline = Ban::new(BanKind::G, "bob@10.0.0.0/8", "spam", "alice", None);
+    assert!(kline.matches("bob", "host.example.com", ip));
+    assert!(!kline.matches("joe", "host.example.com", ip));
+
+    let dline = Ban::new(BanKind::D, "10.0.0.0/24", "spam", "alice", None);
+    assert!(dline.matches("", "", ip));
+    assert!(!dline.matches("", "", Some("10.0.1.5".parse().unwrap())));
+}
+
+#[test]
+fn test_ban_list() {
+    let mut bans = BanList::new();
+    let ip = "10.0.0.5".parse().unwrap();
+
+    bans.add(Ban::new(BanKind::K, "bob@*", "first", "alice", None)).unwrap();
+    bans.add(Ban::new(BanKind::K, "bob@*", "second", "alice", None)).unwrap();
+    bans.add(Ban::new(BanKind::D, "10.*", "dline", "alice", None)).unwrap();
+    assert_eq!(2, bans.bans.len());
+
+    assert_eq!("second", bans.find_kline("bob", "host", None).unwrap().reason);
+    assert!(bans.find_kline("joe", "host", Some(ip)).is_none());
+    assert_eq!("dline", bans.find_dline(ip).unwrap().reason);
+
+    assert!(bans.remove(BanKind::K, "bob@*").unwrap());
+    assert!(!bans.remove(BanKind::K, "bob@*").unwrap());
+    assert!(bans.find_kline("bob", "host", None).is_none());
+}
+
+#[test]
+fn test_ban_expiry() {
+    let mut bans = BanList::new();
+    let duration = Some(Duration::from_secs(60));
+    bans.add(Ban::new(BanKind::K, "bob@*", "spam", "alice", duration)).unwrap();
+
+    assert!(bans.find_kline("bob", "host", None).is_some());
+
+    bans.expire(SystemTime::now() + Duration::from_secs(61)).unwrap();
+    assert!(bans.bans.is_empty());
+}
+
+#[test]
+fn test_save_and_load() {
+    let path = std::env::temp_dir().join(format!("avalon-bans-{}.toml", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut bans = BanList::load(&path).unwrap();
+    assert!(bans.bans.is_empty());
+    bans.add(Ban::new(BanKind::G, "*@*.example.com", "spam", "alice", None)).unwrap();
+    bans.add(Ban::new(BanKind::D, "10.0.0.0/8", "flood", "alice", Some(Duration::from_secs(60))))
+        .unwrap();
+
+    let loaded = BanList::load(&path).unwrap();
+    assert_eq!(bans.bans, loaded.bans);
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,196 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+#[cfg(test)]
+#[path = "./batch_test.rs"]
+mod batch_test;
+
+pub const MULTILINE_BATCH_TYPE: &str = "draft/multiline";
+pub const MULTILINE_CONCAT_TAG: &str = "draft/multiline-concat";
+pub const MULTILINE_MAX_BYTES: usize = 4096;
+pub const MULTILINE_MAX_LINES: usize = 100;
+
+/// Builds an outgoing `BATCH +ref type params ... BATCH -ref` block.
+///
+/// Lines are pushed without the trailing CRLF; `build` tags each one with
+/// `batch=<ref>` and wraps them in the opening and closing BATCH commands.
+pub struct Batch {
+    reference: String,
+    source: String,
+    batch_type: String,
+    params: Vec<String>,
+    lines: Vec<String>,
+}
+
+impl Batch {
+    pub fn new(source: &str, batch_type: &str) -> Self {
+        let reference = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(10)
+            .map(char::from)
+            .collect();
+
+        Batch {
+            reference,
+            source: source.into(),
+            batch_type: batch_type.into(),
+            params: vec![],
+            lines: vec![],
+        }
+    }
+
+    pub fn param(mut self, param: &str) -> Self {
+        self.params.push(param.into());
+        self
+    }
+
+    pub fn push(&mut self, line: &str) {
+        self.push_tagged(&[], line);
+    }
+
+    pub fn push_tagged(&mut self, tags: &[&str], line: &str) {
+        let mut all_tags = format!("batch={}", self.reference);
+        for tag in tags {
+            all_tags.push(';');
+            all_tags.push_str(tag);
+        }
+
+        let line = match line.strip_prefix('@') {
+            Some(tagged) => format!("@{};{}", all_tags, tagged),
+            None => format!("@{} {}", all_tags, line),
+        };
+        self.lines.push(line);
+    }
+
+    pub fn build(&self) -> String {
+        let mut header = format!(
+            ":{} BATCH +{} {}",
+            self.source, self.reference, self.batch_type
+        );
+        for param in &self.params {
+            header.push(' ');
+            header.push_str(param);
+        }
+
+        let mut result = header;
+        result.push_str("\r\n");
+        for line in &self.lines {
+            result.push_str(line);
+            result.push_str("\r\n");
+        }
+        result.push_str(&format!(":{} BATCH -{}\r\n", self.source, self.reference));
+
+        result
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MultilineError {
+    MaxBytes,
+    MaxLines,
+    InvalidTarget,
+}
+
+impl MultilineError {
+    pub fn fail_message(&self, host: &str) -> String {
+        match self {
+            MultilineError::MaxBytes => format!(
+                ":{} FAIL BATCH MULTILINE_MAX_BYTES {} :Multiline batch max-bytes exceeded\r\n",
+                host, MULTILINE_MAX_BYTES
+            ),
+            MultilineError::MaxLines => format!(
+                ":{} FAIL BATCH MULTILINE_MAX_LINES {} :Multiline batch max-lines exceeded\r\n",
+                host, MULTILINE_MAX_LINES
+            ),
+            MultilineError::InvalidTarget => format!(
+                ":{} FAIL BATCH MULTILINE_INVALID_TARGET :Mismatched target in multiline batch\r\n",
+                host
+            ),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MultilineLine {
+    pub text: String,
+    pub concat: bool,
+}
+
+/// Collects the PRIVMSG lines of an inbound `draft/multiline` batch until
+/// the client closes it with `BATCH -ref`.
+#[derive(Debug)]
+pub struct MultilineBuffer {
+    pub reference: String,
+    pub target: String,
+    pub lines: Vec<MultilineLine>,
+    pub failed: bool,
+    // Cleared when any line was converted from a fallback charset.
+    pub utf8: bool,
+    bytes: usize,
+}
+
+impl MultilineBuffer {
+    pub fn new(reference: &str, target: &str) -> Self {
+        MultilineBuffer {
+            reference: reference.into(),
+            target: target.into(),
+            lines: vec![],
+            failed: false,
+            utf8: true,
+            bytes: 0,
+        }
+    }
+
+    pub fn push(&mut self, target: &str, text: &str, concat: bool) -> Result<(), MultilineError> {
+        if target != self.target {
+            return Err(MultilineError::InvalidTarget);
+        }
+
+        if self.lines.len() + 1 > MULTILINE_MAX_LINES {
+            return Err(MultilineError::MaxLines);
+        }
+
+        // Every line but the first adds the newline that joins it to the previous one.
+        let separator = if self.lines.is_empty() || concat { 0 } else { 1 };
+        if self.bytes + separator + text.len() > MULTILINE_MAX_BYTES {
+            return Err(MultilineError::MaxBytes);
+        }
+
+        self.bytes += separator + text.len();
+        self.lines.push(MultilineLine {
+            text: text.into(),
+            concat,
+        });
+
+        Ok(())
+    }
+
+    pub fn to_batch(&self, source: &str) -> Batch {
+        let mut batch = Batch::new(source, MULTILINE_BATCH_TYPE).param(&self.target);
+
+        for line in &self.lines {
+            let privmsg = format!(":{} PRIVMSG {} :{}", source, self.target, line.text);
+            if line.concat {
+                batch.push_tagged(&[MULTILINE_CONCAT_TAG], &privmsg);
+            } else {
+                batch.push(&privmsg);
+            }
+        }
+
+        batch
+    }
+
+    /// Plain PRIVMSG lines for recipients that did not negotiate `draft/multiline`.
+    pub fn fallback(&self, source: &str) -> String {
+        let mut result = String::new();
+
+        for line in self.lines.iter().filter(|l| !l.text.is_empty()) {
+            result.push_str(&format!(
+                ":{} PRIVMSG {} :{}\r\n",
+                source, self.target, line.text
+            ));
+        }
+
+        result
+    }
+}
@@ -0,0 +1,64 @@
+use super::*;
+
+#[test]
+fn test_build_batch() {
+    let mut batch = Batch::new("irc.example", "chathistory").param("#room1");
+    batch.push(":bob!bob@host PRIVMSG #room1 :one");
+    batch.push_tagged(&["draft/multiline-concat"], ":bob!bob@host PRIVMSG #room1 :two");
+    batch.push("@time=123 :bob!bob@host PRIVMSG #room1 :three");
+
+    let reference = batch.reference.clone();
+    let expected = format!(
+        ":irc.example BATCH +{r} chathistory #room1\r\n\
+         @batch={r} :bob!bob@host PRIVMSG #room1 :one\r\n\
+         @batch={r};draft/multiline-concat :bob!bob@host PRIVMSG #room1 :two\r\n\
+         @batch={r};time=123 :bob!bob@host PRIVMSG #room1 :three\r\n\
+         :irc.example BATCH -{r}\r\n",
+        r = reference
+    );
+
+    assert_eq!(expected, batch.build());
+}
+
+#[test]
+fn test_multiline_limits() {
+    let mut buffer = MultilineBuffer::new("ref1", "#room1");
+
+    assert_eq!(
+        Err(MultilineError::InvalidTarget),
+        buffer.push("#room2", "hello", false)
+    );
+
+    let long_line = "a".repeat(MULTILINE_MAX_BYTES);
+    assert_eq!(Ok(()), buffer.push("#room1", &long_line, false));
+    assert_eq!(
+        Err(MultilineError::MaxBytes),
+        buffer.push("#room1", "", false)
+    );
+
+    let mut buffer = MultilineBuffer::new("ref2", "#room1");
+    for _ in 0..MULTILINE_MAX_LINES {
+        assert_eq!(Ok(()), buffer.push("#room1", "x", false));
+    }
+    assert_eq!(
+        Err(MultilineError::MaxLines),
+        buffer.push("#room1", "x", false)
+    );
+}
+
+#[test]
+fn test_multiline_fallback() {
+    let mut buffer = MultilineBuffer::new("ref1", "#room1");
+    buffer.push("#room1", "first", false).unwrap();
+    buffer.push("#room1", "", false).unwrap();
+    buffer.push("#room1", "second", true).unwrap();
+
+    assert_eq!(
+        ":bob!bob@host PRIVMSG #room1 :first\r\n:bob!bob@host PRIVMSG #room1 :second\r\n",
+        buffer.fallback("bob!bob@host")
+    );
+
+    let batch = buffer.to_batch("bob!bob@host").build();
+    assert!(batch.contains(";draft/multiline-concat :bob!bob@host PRIVMSG #room1 :second\r\n"));
+    assert_eq!(5, batch.lines().count());
+}
@@ -0,0 +1,59 @@
+use crate::batch::{MULTILINE_MAX_BYTES, MULTILINE_MAX_LINES};
+use crate::config::Config;
+
+pub const BATCH: &str = "batch";
+pub const MULTILINE: &str = "draft/multiline";
+pub const INVITE_NOTIFY: &str = "invite-notify";
+pub const SASL: &str = "sasl";
+// Only ever advertised, clients cannot request it.
+pub const STS: &str = "sts";
+
+pub const SASL_MECHANISMS: &str = "EXTERNAL";
+
+const SUPPORTED: &[&str] = &[BATCH, MULTILINE, INVITE_NOTIFY, SASL];
+
+pub fn is_supported(cap: &str) -> bool {
+    SUPPORTED.contains(&cap)
+}
+
+fn value(cap: &str) -> Option<String> {
+    match cap {
+        MULTILINE => Some(format!(
+            "max-bytes={},max-lines={}",
+            MULTILINE_MAX_BYTES, MULTILINE_MAX_LINES
+        )),
+        SASL => Some(SASL_MECHANISMS.into()),
+        _ => None,
+    }
+}
+
+// Plaintext clients are told which port to upgrade to, TLS clients how long
+// to keep the policy.
+pub fn sts_policy(config: &Config, secure: bool) -> Option<String> {
+    let duration = config.tls.as_ref()?.sts_duration?;
+
+    if secure {
+        Some(format!("duration={}", duration))
+    } else {
+        let port = config.sts_port()?;
+        Some(format!("port={},duration={}", port, duration))
+    }
+}
+
+// The `sts` value is sent even without 302, the cap means nothing without it.
+pub fn ls(with_values: bool, sts: Option<&str>) -> String {
+    let mut caps = vec![];
+
+    for cap in SUPPORTED {
+        match value(cap) {
+            Some(value) if with_values => caps.push(format!("{}={}", cap, value)),
+            _ => caps.push(cap.to_string()),
+        }
+    }
+
+    if let Some(policy) = sts {
+        caps.push(format!("{}={}", STS, policy));
+    }
+
+    caps.join(" ")
+}
@@ -1,16 +1,143 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::time::SystemTime;
 
-static EMPTY_SET: Lazy<HashSet<String>> = Lazy::new(|| HashSet::new());
+use crate::elist::ListEntry;
+use crate::messages::{format_mode_changes, parse_mode_changes};
+
+pub const CHANNEL_MODES: &str = "ipsUz";
+
+static EMPTY_MEMBERS: Lazy<HashMap<String, Membership>> = Lazy::new(HashMap::new);
 
 #[cfg(test)]
 #[path = "./channels_test.rs"]
 mod channels_test;
 
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Membership {
+    pub op: bool,
+    pub voice: bool,
+}
+
+impl Membership {
+    pub fn prefix(&self) -> &'static str {
+        if self.op {
+            "@"
+        } else if self.voice {
+            "+"
+        } else {
+            ""
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Topic {
+    pub text: String,
+    pub time: SystemTime,
+}
+
+#[derive(Debug)]
+pub struct Channel {
+    pub members: HashMap<String, Membership>,
+    pub modes: HashSet<char>,
+    pub topic: Option<Topic>,
+    pub created: SystemTime,
+    pub invites: HashSet<String>,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Channel {
+            members: HashMap::new(),
+            modes: HashSet::new(),
+            topic: None,
+            created: SystemTime::now(),
+            invites: HashSet::new(),
+        }
+    }
+
+    pub fn mode_string(&self) -> String {
+        let mut modes = self.modes.iter().collect::<Vec<_>>();
+        modes.sort();
+
+        let mut result = String::from("+");
+        result.extend(modes);
+        result
+    }
+
+    // Returns the changes that actually took effect, in `+ab-c` form, and
+    // the mode characters that are not supported.
+    pub fn apply_modes(&mut self, modes: &str) -> (String, Vec<char>) {
+        let mut applied = vec![];
+        let mut unknown = vec![];
+
+        for (adding, c) in parse_mode_changes(modes) {
+            if !CHANNEL_MODES.contains(c) {
+                unknown.push(c);
+                continue;
+            }
+
+            let changed = if adding {
+                self.modes.insert(c)
+            } else {
+                self.modes.remove(&c)
+            };
+            if changed {
+                applied.push((adding, c));
+            }
+        }
+
+        (format_mode_changes(&applied), unknown)
+    }
+
+    pub fn is_invite_only(&self) -> bool {
+        self.modes.contains(&'i')
+    }
+
+    // +U: only relay messages that the client sent as UTF-8.
+    pub fn is_utf8_only(&self) -> bool {
+        self.modes.contains(&'U')
+    }
+
+    // +z: only connections over TLS can join.
+    pub fn is_secure_only(&self) -> bool {
+        self.modes.contains(&'z')
+    }
+
+    pub fn list_entry(&self, name: &str) -> ListEntry {
+        ListEntry {
+            name: name.into(),
+            users: self.members.len(),
+            topic: self.topic.as_ref().map(|t| t.text.clone()).unwrap_or_default(),
+            created: self.created,
+            topic_time: self.topic.as_ref().map(|t| t.time),
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        if self.modes.contains(&'s') {
+            "@"
+        } else if self.modes.contains(&'p') {
+            "*"
+        } else {
+            "="
+        }
+    }
+
+    pub fn is_secret(&self) -> bool {
+        self.modes.contains(&'s') || self.modes.contains(&'p')
+    }
+
+    pub fn is_member(&self, nick: &str) -> bool {
+        self.members.contains_key(nick)
+    }
+}
+
 #[derive(Debug)]
 pub struct Channels {
-    channels_map: HashMap<String, HashSet<String>>,
+    channels_map: HashMap<String, Channel>,
 }
 
 impl Channels {
@@ -25,21 +152,105 @@ impl Channels {
 
         match channel_map {
             Some(chan) => {
-                chan.insert(nick.into());
+                chan.invites.remove(nick);
+                chan.members.entry(nick.into()).or_default();
             }
             None => {
-                let mut set = HashSet::new();
-                set.insert(nick.into());
-                self.channels_map.insert(channel.into(), set);
+                let mut chan = Channel::new();
+                chan.members.insert(
+                    nick.into(),
+                    Membership {
+                        op: true,
+                        voice: false,
+                    },
+                );
+                self.channels_map.insert(channel.into(), chan);
             }
         }
     }
 
+    pub fn part_user(&mut self, channel: &str, nick: &str) -> bool {
+        let Some(chan) = self.channels_map.get_mut(channel) else {
+            return false;
+        };
+
+        let removed = chan.members.remove(nick).is_some();
+        if chan.members.is_empty() {
+            self.channels_map.remove(channel);
+        }
+
+        removed
+    }
+
+    // Invites go with the user, so that whoever takes the nick next does
+    // not inherit them.
+    pub fn remove_user(&mut self, nick: &str) -> Vec<String> {
+        let channels = self.user_channels(nick);
+
+        for channel in &channels {
+            self.part_user(channel, nick);
+        }
+        for chan in self.channels_map.values_mut() {
+            chan.invites.remove(nick);
+        }
+
+        channels
+    }
+
+    pub fn rename_user(&mut self, old_nick: &str, new_nick: &str) -> Vec<String> {
+        let channels = self.user_channels(old_nick);
+
+        for channel in &channels {
+            if let Some(chan) = self.channels_map.get_mut(channel) {
+                if let Some(membership) = chan.members.remove(old_nick) {
+                    chan.members.insert(new_nick.into(), membership);
+                }
+            }
+        }
+        for chan in self.channels_map.values_mut() {
+            if chan.invites.remove(old_nick) {
+                chan.invites.insert(new_nick.into());
+            }
+        }
+
+        channels
+    }
+
+    pub fn user_channels(&self, nick: &str) -> Vec<String> {
+        self.channels_map
+            .iter()
+            .filter(|(_, chan)| chan.is_member(nick))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub fn peers(&self, nick: &str) -> HashSet<String> {
+        self.channels_map
+            .values()
+            .filter(|chan| chan.is_member(nick))
+            .flat_map(|chan| chan.members.keys())
+            .filter(|member| *member != nick)
+            .cloned()
+            .collect()
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        self.channels_map.keys().cloned().collect()
+    }
+
+    pub fn get(&self, channel: &str) -> Option<&Channel> {
+        self.channels_map.get(channel)
+    }
+
+    pub fn get_mut(&mut self, channel: &str) -> Option<&mut Channel> {
+        self.channels_map.get_mut(channel)
+    }
+
     pub fn channel_list(&self, channel: &str) -> impl Iterator<Item = &String> {
         if let Some(chan) = self.channels_map.get(channel) {
-            return chan.into_iter();
+            return chan.members.keys();
         }
 
-        EMPTY_SET.iter()
+        EMPTY_MEMBERS.keys()
     }
 }
@@ -8,7 +8,7 @@ fn test_join_channels() {
     channels.join_user("#room2", "bob");
     channels.join_user("#room3", "bob");
 
-    let expected_rooms = vec!["#room1", "#room2", "#room3"]
+    let expected_rooms = ["#room1", "#room2", "#room3"]
         .iter()
         .map(|s| s.to_string())
         .collect::<HashSet<_>>();
@@ -29,7 +29,7 @@ fn test_list_users() {
     channels.join_user("#room1", "ana");
     channels.join_user("#room1", "ricardo");
 
-    let expected_users = vec!["bob", "ana", "ricardo"]
+    let expected_users = ["bob", "ana", "ricardo"]
         .iter()
         .map(|s| s.to_string())
         .collect::<HashSet<_>>();
@@ -43,3 +43,48 @@ fn test_list_users() {
 
     assert!(channels.channel_list("#room2").count() == 0);
 }
+
+#[test]
+fn test_apply_modes() {
+    let mut channel = Channel::new();
+
+    let (applied, unknown) = channel.apply_modes("+isx");
+    assert_eq!("+is", applied);
+    assert_eq!(vec!['x'], unknown);
+    assert_eq!("+is", channel.mode_string());
+
+    let (applied, unknown) = channel.apply_modes("+i-sp+p");
+    assert_eq!("-s+p", applied);
+    assert!(unknown.is_empty());
+    assert_eq!("+ip", channel.mode_string());
+}
+
+#[test]
+fn test_first_user_is_op_and_invite_is_used_up() {
+    let mut channels = Channels::new();
+
+    channels.join_user("#room1", "bob");
+    channels.get_mut("#room1").unwrap().invites.insert("ana".into());
+    channels.join_user("#room1", "ana");
+
+    let chan = channels.get("#room1").unwrap();
+    assert!(chan.members["bob"].op);
+    assert!(!chan.members["ana"].op);
+    assert!(chan.invites.is_empty());
+}
+
+#[test]
+fn test_invites_follow_the_user() {
+    let mut channels = Channels::new();
+
+    channels.join_user("#room1", "bob");
+    let chan = channels.get_mut("#room1").unwrap();
+    chan.invites.insert("ana".into());
+    chan.invites.insert("joe".into());
+
+    channels.rename_user("ana", "anna");
+    channels.remove_user("joe");
+
+    let chan = channels.get("#room1").unwrap();
+    assert_eq!(HashSet::from(["anna".to_string()]), chan.invites);
+}
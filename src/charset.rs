@@ -0,0 +1,62 @@
+use serde::Deserialize;
+
+#[cfg(test)]
+#[path = "./charset_test.rs"]
+mod charset_test;
+
+/// What to do with client lines that are not valid UTF-8. Valid UTF-8 is
+/// always taken as it is, whatever the policy.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Charset {
+    // Drop the line and tell the client.
+    #[default]
+    Reject,
+    // Replace the invalid bytes with U+FFFD.
+    Replace,
+    #[serde(alias = "iso-8859-1")]
+    Latin1,
+    #[serde(alias = "windows-1252")]
+    Cp1252,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Input {
+    pub text: String,
+    // False when `text` had to be converted from something else.
+    pub utf8: bool,
+}
+
+// 0x80..=0x9F in windows-1252. The five unassigned bytes map to the C1
+// control with the same value, as browsers do.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+impl Charset {
+    // Returns `None` when the line has to be rejected.
+    pub fn decode(&self, line: Vec<u8>) -> Option<Input> {
+        let line = match String::from_utf8(line) {
+            Ok(text) => return Some(Input { text, utf8: true }),
+            Err(e) => e.into_bytes(),
+        };
+
+        let text = match self {
+            Charset::Reject => return None,
+            Charset::Replace => String::from_utf8_lossy(&line).into_owned(),
+            Charset::Latin1 => line.iter().map(|b| char::from(*b)).collect(),
+            Charset::Cp1252 => line
+                .iter()
+                .map(|b| match b {
+                    0x80..=0x9F => CP1252_HIGH[(b - 0x80) as usize],
+                    _ => char::from(*b),
+                })
+                .collect(),
+        };
+
+        Some(Input { text, utf8: false })
+    }
+}
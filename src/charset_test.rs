@@ -0,0 +1,52 @@
+use super::*;
+
+fn input(text: &str, utf8: bool) -> Option<Input> {
+    Some(Input {
+        text: text.into(),
+        utf8,
+    })
+}
+
+#[test]
+fn test_utf8_is_kept() {
+    for charset in [Charset::Reject, Charset::Replace, Charset::Latin1, Charset::Cp1252] {
+        assert_eq!(
+            input("PRIVMSG #rust :café", true),
+            charset.decode("PRIVMSG #rust :café".into())
+        );
+    }
+}
+
+#[test]
+fn test_invalid_utf8() {
+    let line = b"PRIVMSG #rust :caf\xe9 \x80".to_vec();
+
+    assert_eq!(None, Charset::Reject.decode(line.clone()));
+    assert_eq!(
+        input("PRIVMSG #rust :caf\u{FFFD} \u{FFFD}", false),
+        Charset::Replace.decode(line.clone())
+    );
+    assert_eq!(
+        input("PRIVMSG #rust :café \u{0080}", false),
+        Charset::Latin1.decode(line.clone())
+    );
+    assert_eq!(
+        input("PRIVMSG #rust :café €", false),
+        Charset::Cp1252.decode(line)
+    );
+}
+
+#[test]
+fn test_parse_charset() {
+    #[derive(Deserialize)]
+    struct Config {
+        charset: Charset,
+    }
+
+    let parse = |s: &str| toml::from_str::<Config>(&format!("charset = \"{}\"", s)).unwrap().charset;
+
+    assert_eq!(Charset::Reject, parse("reject"));
+    assert_eq!(Charset::Replace, parse("replace"));
+    assert_eq!(Charset::Latin1, parse("iso-8859-1"));
+    assert_eq!(Charset::Cp1252, parse("windows-1252"));
+}
@@ -0,0 +1,120 @@
+use std::io::Error;
+
+use tokio_util::bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::messages::{MAX_LINE_LENGTH, MAX_TAGS_LENGTH};
+
+#[cfg(test)]
+#[path = "./codec_test.rs"]
+mod codec_test;
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    // A line without its `\r\n` or `\n` terminator. It is not required to
+    // be valid UTF-8.
+    Line(Vec<u8>),
+    // A line went over the length limit and was dropped.
+    TooLong,
+}
+
+/// Frames client input on `\r\n` or a lone `\n`. Lines are limited to
+/// MAX_LINE_LENGTH bytes, terminator included, and once the client can send
+/// tags the `@tags ` prefix gets its own MAX_TAGS_LENGTH budget.
+#[derive(Debug, Default)]
+pub struct IrcCodec {
+    tags: bool,
+    // Set while skipping the rest of a line that was already too long.
+    discarding: bool,
+}
+
+impl IrcCodec {
+    pub fn new() -> Self {
+        IrcCodec::default()
+    }
+
+    pub fn set_tags(&mut self, tags: bool) {
+        self.tags = tags;
+    }
+
+    fn max_length(&self) -> usize {
+        if self.tags {
+            MAX_TAGS_LENGTH + MAX_LINE_LENGTH
+        } else {
+            MAX_LINE_LENGTH
+        }
+    }
+
+    fn check_length(&self, line: &[u8]) -> bool {
+        let tags_length = match line.first() {
+            Some(b'@') if self.tags => match line.iter().position(|b| *b == b' ') {
+                Some(space) => space + 1,
+                None => line.len(),
+            },
+            _ => 0,
+        };
+
+        tags_length <= MAX_TAGS_LENGTH && line.len() - tags_length <= MAX_LINE_LENGTH
+    }
+}
+
+impl Decoder for IrcCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        let Some(end) = src.iter().position(|b| *b == b'\n') else {
+            if src.len() > self.max_length() {
+                src.clear();
+                if !self.discarding {
+                    self.discarding = true;
+                    return Ok(Some(Frame::TooLong));
+                }
+            }
+            return Ok(None);
+        };
+
+        let line = src.split_to(end + 1);
+        if self.discarding {
+            self.discarding = false;
+            return self.decode(src);
+        }
+        if !self.check_length(&line) {
+            return Ok(Some(Frame::TooLong));
+        }
+
+        let mut line = line.to_vec();
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        Ok(Some(Frame::Line(line)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Error> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+
+        // A last line without terminator is still handled.
+        if src.is_empty() || self.discarding {
+            src.clear();
+            return Ok(None);
+        }
+        src.put_u8(b'\n');
+        self.decode(src)
+    }
+}
+
+// Replies are built with their own `\r\n`, and a batch can hold several
+// lines, so they are written as they are.
+impl Encoder<String> for IrcCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: String, dst: &mut BytesMut) -> Result<(), Error> {
+        dst.reserve(item.len());
+        dst.put(item.as_bytes());
+        Ok(())
+    }
+}
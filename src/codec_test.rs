@@ -0,0 +1,92 @@
+use super::*;
+
+fn line(text: &[u8]) -> Option<Frame> {
+    Some(Frame::Line(text.to_vec()))
+}
+
+#[test]
+fn test_decode_lines() {
+    let mut codec = IrcCodec::new();
+    let mut src = BytesMut::from(&b"NICK bob\r\nUSER bob 0 * :Bob\nPI"[..]);
+
+    assert_eq!(line(b"NICK bob"), codec.decode(&mut src).unwrap());
+    assert_eq!(line(b"USER bob 0 * :Bob"), codec.decode(&mut src).unwrap());
+    assert_eq!(None, codec.decode(&mut src).unwrap());
+
+    src.put(&b"NG\r\n"[..]);
+    assert_eq!(line(b"PING"), codec.decode(&mut src).unwrap());
+    assert!(src.is_empty());
+}
+
+#[test]
+fn test_decode_invalid_utf8() {
+    let mut codec = IrcCodec::new();
+    let mut src = BytesMut::from(&b"PRIVMSG bob :caf\xe9\r\n"[..]);
+
+    assert_eq!(line(b"PRIVMSG bob :caf\xe9"), codec.decode(&mut src).unwrap());
+}
+
+#[test]
+fn test_decode_eof() {
+    let mut codec = IrcCodec::new();
+    let mut src = BytesMut::from(&b"PING 1\r\nQUIT"[..]);
+
+    assert_eq!(line(b"PING 1"), codec.decode_eof(&mut src).unwrap());
+    assert_eq!(line(b"QUIT"), codec.decode_eof(&mut src).unwrap());
+    assert_eq!(None, codec.decode_eof(&mut src).unwrap());
+}
+
+#[test]
+fn test_line_too_long() {
+    let mut codec = IrcCodec::new();
+    let long = format!("PRIVMSG bob :{}\r\n", "a".repeat(MAX_LINE_LENGTH));
+    let mut src = BytesMut::from(format!("{}PING 1\r\n", long).as_bytes());
+
+    assert_eq!(Some(Frame::TooLong), codec.decode(&mut src).unwrap());
+    assert_eq!(line(b"PING 1"), codec.decode(&mut src).unwrap());
+
+    let exact = format!("PRIVMSG bob :{}\r\n", "a".repeat(MAX_LINE_LENGTH - 15));
+    assert_eq!(MAX_LINE_LENGTH, exact.len());
+    let mut src = BytesMut::from(exact.as_bytes());
+    assert!(matches!(codec.decode(&mut src).unwrap(), Some(Frame::Line(_))));
+}
+
+#[test]
+fn test_unterminated_line_too_long() {
+    let mut codec = IrcCodec::new();
+    let mut src = BytesMut::from("a".repeat(MAX_LINE_LENGTH + 1).as_bytes());
+
+    assert_eq!(Some(Frame::TooLong), codec.decode(&mut src).unwrap());
+    assert!(src.is_empty());
+
+    // The rest of the line is dropped without a second error.
+    src.put(&b"aaaa\r\nPING 1\r\n"[..]);
+    assert_eq!(line(b"PING 1"), codec.decode(&mut src).unwrap());
+}
+
+#[test]
+fn test_tag_budget() {
+    let tagged = format!("@batch={} PRIVMSG bob :hi\r\n", "a".repeat(1000));
+
+    let mut codec = IrcCodec::new();
+    let mut src = BytesMut::from(tagged.as_bytes());
+    assert_eq!(Some(Frame::TooLong), codec.decode(&mut src).unwrap());
+
+    codec.set_tags(true);
+    let mut src = BytesMut::from(tagged.as_bytes());
+    assert!(matches!(codec.decode(&mut src).unwrap(), Some(Frame::Line(_))));
+
+    let tagged = format!("@batch={} PRIVMSG bob :hi\r\n", "a".repeat(MAX_TAGS_LENGTH));
+    let mut src = BytesMut::from(tagged.as_bytes());
+    assert_eq!(Some(Frame::TooLong), codec.decode(&mut src).unwrap());
+}
+
+#[test]
+fn test_encode() {
+    let mut codec = IrcCodec::new();
+    let mut dst = BytesMut::new();
+
+    codec.encode("PING :a\r\n".to_string(), &mut dst).unwrap();
+    codec.encode("PING :b\r\n".to_string(), &mut dst).unwrap();
+    assert_eq!(&b"PING :a\r\nPING :b\r\n"[..], &dst[..]);
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::bans::ip_matches;
+use crate::charset::Charset;
+use crate::sendq::DEFAULT_SENDQ;
+use crate::tls::same_fingerprint;
+
+#[cfg(test)]
+#[path = "./config_test.rs"]
+mod config_test;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub opers: Vec<OperBlock>,
+    #[serde(default)]
+    pub classes: HashMap<String, OperClass>,
+    // Connection classes, which listeners can put their clients in.
+    #[serde(default)]
+    pub connect_classes: HashMap<String, ConnectClass>,
+    #[serde(default)]
+    pub accounts: Vec<AccountBlock>,
+    // Gateways that may pass on the address of their users with WEBIRC.
+    #[serde(default)]
+    pub webirc: Vec<WebIrcBlock>,
+    // Client certificate fingerprints exempt from K-lines and G-lines.
+    #[serde(default)]
+    pub kline_exempt: Vec<String>,
+    // Where K/D/G-lines are persisted. Without it bans only live in memory.
+    pub ban_file: Option<PathBuf>,
+    #[serde(default)]
+    pub flood: FloodConfig,
+    // Bytes that can be waiting to be written to a client before it is
+    // dropped with "SendQ exceeded".
+    #[serde(default = "default_sendq")]
+    pub sendq: usize,
+    // How lines that are not valid UTF-8 are handled.
+    #[serde(default)]
+    pub charset: Charset,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub lookup: LookupConfig,
+    // Without any, the server listens on 0.0.0.0:6667 and on the [tls]
+    // port when TLS is configured.
+    #[serde(default)]
+    pub listen: Vec<ListenBlock>,
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OperBlock {
+    pub name: String,
+    // bcrypt hash, e.g. the output of `htpasswd -nbBC 10 "" password`. It
+    // can be left out when the block requires a client certificate.
+    pub password: Option<String>,
+    pub certfp: Option<String>,
+    #[serde(default = "any_host")]
+    pub hosts: Vec<String>,
+    pub class: String,
+}
+
+// Accounts that can log in with SASL EXTERNAL, by client certificate.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccountBlock {
+    pub name: String,
+    #[serde(default)]
+    pub certfp: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebIrcBlock {
+    // bcrypt hash of the password the gateway sends.
+    pub password: String,
+    // Addresses, as CIDRs or masks, the gateway connects from. Unix socket
+    // connections count as 127.0.0.1.
+    pub hosts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OperClass {
+    #[serde(default)]
+    pub privileges: Vec<String>,
+}
+
+// Overrides the server-wide limits for the clients in the class.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConnectClass {
+    pub sendq: Option<usize>,
+    pub flood: Option<FloodConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    // PEM certificate chain and private key, read again on REHASH.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // Port of the TLS listener when there are no [[listen]] blocks.
+    #[serde(default = "default_tls_port")]
+    pub port: u16,
+    // Seconds clients should keep connecting over TLS once they have seen
+    // the `sts` cap. It is only advertised when this is set.
+    pub sts_duration: Option<u64>,
+}
+
+// What the server finds out about clients as they connect.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LookupConfig {
+    // Forward-confirmed reverse DNS. Clients without a verified name get
+    // their address as host, instead of the one they send with USER.
+    pub dns: bool,
+    // RFC 1413 ident queries, for the user name.
+    pub ident: bool,
+    // Seconds each lookup can take.
+    pub timeout: u64,
+}
+
+impl Default for LookupConfig {
+    fn default() -> Self {
+        LookupConfig {
+            dns: true,
+            ident: false,
+            timeout: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ListenBlock {
+    // Exactly one of `address`, for TCP over IPv4 or IPv6, and `path`, for
+    // a Unix domain socket.
+    pub address: Option<SocketAddr>,
+    pub path: Option<PathBuf>,
+    // Permissions of the socket file, e.g. 0o660.
+    pub mode: Option<u32>,
+    // Use the certificate from [tls].
+    pub tls: bool,
+    pub websocket: bool,
+    // Origin headers, as masks, that browsers may connect to a WebSocket
+    // listener from. Any page can connect when it is empty.
+    pub origins: Vec<String>,
+    // Falls back to the server-wide charset.
+    pub charset: Option<Charset>,
+    // A connection class from [connect_classes].
+    pub class: Option<String>,
+    // Whether clients may connect here at all.
+    pub clients: bool,
+    // bcrypt hash of the password clients have to send with PASS.
+    pub password: Option<String>,
+    // Load balancers, as CIDRs or masks, that send a PROXY protocol v1 or v2
+    // header first. When set, connections from anywhere else are refused.
+    // The address in the header is the one D-lines, bans and hostmasks go
+    // by; there are no per-address connection limits to apply it to yet.
+    // TCP only, as a Unix socket cannot tell who the balancer is.
+    pub proxy: Vec<String>,
+}
+
+impl Default for ListenBlock {
+    fn default() -> Self {
+        ListenBlock {
+            address: None,
+            path: None,
+            mode: None,
+            tls: false,
+            websocket: false,
+            origins: vec![],
+            charset: None,
+            class: None,
+            clients: true,
+            password: None,
+            proxy: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FloodConfig {
+    // Commands a client can send at once before being throttled.
+    pub burst: u32,
+    // Commands per second the bucket refills with.
+    pub rate: f64,
+    // Throttled commands in a row tolerated before "Excess Flood".
+    pub excess: u32,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        FloodConfig {
+            burst: 20,
+            rate: 2.0,
+            excess: 20,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            opers: vec![],
+            classes: HashMap::new(),
+            accounts: vec![],
+            webirc: vec![],
+            kline_exempt: vec![],
+            ban_file: None,
+            flood: FloodConfig::default(),
+            sendq: DEFAULT_SENDQ,
+            charset: Charset::default(),
+            connect_classes: HashMap::new(),
+            tls: None,
+            lookup: LookupConfig::default(),
+            listen: vec![],
+            path: None,
+        }
+    }
+}
+
+fn default_sendq() -> usize {
+    DEFAULT_SENDQ
+}
+
+fn default_tls_port() -> u16 {
+    6697
+}
+
+fn any_host() -> Vec<String> {
+    vec!["*@*".into()]
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read {}", path.display()))?;
+        let mut config = Config::parse(&contents)?;
+        config.path = Some(path.into());
+
+        Ok(config)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config> {
+        let config: Config = toml::from_str(contents)?;
+
+        for listen in &config.listen {
+            if listen.address.is_some() == listen.path.is_some() {
+                bail!("[[listen]] needs either an address or a path");
+            }
+            if listen.tls && config.tls.is_none() {
+                bail!("[[listen]] tls needs the certificate from [tls]");
+            }
+            if listen.path.is_some() && !listen.proxy.is_empty() {
+                bail!("[[listen]] proxy needs an address, not a path");
+            }
+            if let Some(class) = &listen.class {
+                if !config.connect_classes.contains_key(class) {
+                    bail!("[[listen]] uses unknown connection class {}", class);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn listeners(&self) -> Vec<ListenBlock> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+
+        let mut listeners = vec![ListenBlock {
+            address: Some(SocketAddr::from(([0, 0, 0, 0], 6667))),
+            ..ListenBlock::default()
+        }];
+        if let Some(tls) = &self.tls {
+            listeners.push(ListenBlock {
+                address: Some(SocketAddr::from(([0, 0, 0, 0], tls.port))),
+                tls: true,
+                ..ListenBlock::default()
+            });
+        }
+
+        listeners
+    }
+
+    // The port the `sts` cap sends plaintext clients to.
+    pub fn sts_port(&self) -> Option<u16> {
+        self.listeners()
+            .iter()
+            .find(|listen| listen.tls && !listen.websocket)
+            .and_then(|listen| listen.address)
+            .map(|address| address.port())
+    }
+
+    pub fn sendq_for(&self, class: Option<&str>) -> usize {
+        self.connect_class(class)
+            .and_then(|class| class.sendq)
+            .unwrap_or(self.sendq)
+    }
+
+    pub fn flood_for(&self, class: Option<&str>) -> FloodConfig {
+        self.connect_class(class)
+            .and_then(|class| class.flood.clone())
+            .unwrap_or_else(|| self.flood.clone())
+    }
+
+    fn connect_class(&self, class: Option<&str>) -> Option<&ConnectClass> {
+        class.and_then(|class| self.connect_classes.get(class))
+    }
+
+    pub fn find_oper(&self, name: &str) -> Option<&OperBlock> {
+        self.opers.iter().find(|oper| oper.name == name)
+    }
+
+    pub fn find_account(&self, certfp: &str) -> Option<&AccountBlock> {
+        self.accounts
+            .iter()
+            .find(|account| account.certfp.iter().any(|fp| same_fingerprint(fp, certfp)))
+    }
+
+    // The gateways allowed to connect from `ip`. Which one it is, if any,
+    // comes down to the password.
+    pub fn webirc_gateways(&self, ip: IpAddr) -> Vec<WebIrcBlock> {
+        self.webirc
+            .iter()
+            .filter(|block| block.hosts.iter().any(|mask| ip_matches(mask, ip)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_kline_exempt(&self, certfp: Option<&str>) -> bool {
+        certfp.is_some_and(|certfp| self.kline_exempt.iter().any(|fp| same_fingerprint(fp, certfp)))
+    }
+
+    pub fn privileges(&self, class: &str) -> Vec<String> {
+        self.classes
+            .get(class)
+            .map(|c| c.privileges.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl WebIrcBlock {
+    pub fn check_password(&self, password: &str) -> bool {
+        bcrypt::verify(password, &self.password).unwrap_or(false)
+    }
+}
+
+impl OperBlock {
+    pub fn check_certfp(&self, certfp: Option<&str>) -> bool {
+        match &self.certfp {
+            Some(expected) => certfp.is_some_and(|certfp| same_fingerprint(expected, certfp)),
+            None => true,
+        }
+    }
+
+    // A block without a password relies on its certificate alone, one with
+    // neither never matches.
+    pub fn check_password(&self, password: &str) -> bool {
+        match &self.password {
+            Some(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            None => self.certfp.is_some(),
+        }
+    }
+}
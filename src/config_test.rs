@@ -0,0 +1,174 @@
+use super::*;
+
+#[test]
+fn test_parse_config() {
+    let hash = bcrypt::hash("secret", 4).unwrap();
+    let contents = format!(
+        r#"
+        ban_file = "bans.toml"
+        charset = "cp1252"
+        kline_exempt = ["ab:cd:ef"]
+
+        [tls]
+        cert = "avalon.crt"
+        key = "avalon.key"
+        sts_duration = 2592000
+
+        [connect_classes.bots]
+        sendq = 4194304
+
+        [lookup]
+        ident = true
+        timeout = 3
+
+        [[listen]]
+        address = "[::]:6667"
+
+        [[listen]]
+        address = "0.0.0.0:8097"
+        tls = true
+        websocket = true
+        origins = ["https://*.example.com"]
+        proxy = ["10.0.0.0/8"]
+
+        [[listen]]
+        path = "/run/avalon/irc.sock"
+        mode = 0o660
+        class = "bots"
+        password = "hash"
+
+        [[opers]]
+        name = "alice"
+        password = "{}"
+        hosts = ["alice@127.0.0.1", "*@*.example.com"]
+        class = "netadmin"
+
+        [[opers]]
+        name = "bob"
+        password = "{}"
+        class = "helper"
+
+        [[opers]]
+        name = "joe"
+        certfp = "AB:CD:EF"
+        class = "helper"
+
+        [classes.netadmin]
+        privileges = ["kill", "wallops", "rehash"]
+
+        [[accounts]]
+        name = "alice"
+        certfp = ["abcdef", "012345"]
+
+        [[webirc]]
+        password = "{}"
+        hosts = ["10.1.0.0/16", "127.0.0.1"]
+        "#,
+        hash,
+        hash, hash
+    );
+
+    let config = Config::parse(&contents).unwrap();
+
+    let alice = config.find_oper("alice").unwrap();
+    assert_eq!(vec!["alice@127.0.0.1", "*@*.example.com"], alice.hosts);
+    assert!(alice.check_password("secret"));
+    assert!(!alice.check_password("wrong"));
+    assert_eq!(
+        vec!["kill", "wallops", "rehash"],
+        config.privileges(&alice.class)
+    );
+
+    let bob = config.find_oper("bob").unwrap();
+    assert_eq!(vec!["*@*"], bob.hosts);
+    assert!(config.privileges(&bob.class).is_empty());
+
+    let joe = config.find_oper("joe").unwrap();
+    assert!(joe.check_password("anything"));
+    assert!(joe.check_certfp(Some("abcdef")));
+    assert!(!joe.check_certfp(Some("012345")));
+    assert!(!joe.check_certfp(None));
+    assert!(bob.check_certfp(None));
+
+    assert!(config.find_oper("ana").is_none());
+
+    assert_eq!("alice", config.find_account("01:23:45").unwrap().name);
+    assert!(config.find_account("abcdee").is_none());
+
+    let gateways = config.webirc_gateways("10.1.2.3".parse().unwrap());
+    assert!(gateways.iter().any(|gateway| gateway.check_password("secret")));
+    assert!(!gateways.iter().any(|gateway| gateway.check_password("wrong")));
+    assert!(config.webirc_gateways("10.2.0.1".parse().unwrap()).is_empty());
+
+    assert!(config.is_kline_exempt(Some("ABCDEF")));
+    assert!(!config.is_kline_exempt(Some("012345")));
+    assert!(!config.is_kline_exempt(None));
+    assert_eq!(Some(PathBuf::from("bans.toml")), config.ban_file);
+    assert!(config.lookup.dns && config.lookup.ident);
+    assert_eq!(3, config.lookup.timeout);
+    assert_eq!(Charset::Cp1252, config.charset);
+
+    let tls = config.tls.as_ref().unwrap();
+    assert_eq!(PathBuf::from("avalon.crt"), tls.cert);
+    assert_eq!(PathBuf::from("avalon.key"), tls.key);
+    assert_eq!(6697, tls.port);
+    assert_eq!(Some(2592000), tls.sts_duration);
+
+    let [ipv6, websocket, unix] = &config.listeners()[..] else {
+        panic!("expected three listeners");
+    };
+    assert_eq!(Some("[::]:6667".parse().unwrap()), ipv6.address);
+    assert!(!ipv6.tls && ipv6.clients && ipv6.password.is_none());
+    assert!(websocket.tls && websocket.websocket);
+    assert_eq!(vec!["https://*.example.com"], websocket.origins);
+    assert_eq!(vec!["10.0.0.0/8"], websocket.proxy);
+    assert!(ipv6.proxy.is_empty());
+    assert_eq!(Some(PathBuf::from("/run/avalon/irc.sock")), unix.path);
+    assert_eq!(Some(0o660), unix.mode);
+    assert_eq!(Some("hash"), unix.password.as_deref());
+
+    // The WebSocket listener does not count for STS.
+    assert_eq!(None, config.sts_port());
+    assert_eq!(4194304, config.sendq_for(Some("bots")));
+    assert_eq!(DEFAULT_SENDQ, config.sendq_for(None));
+    assert_eq!(20, config.flood_for(Some("bots")).burst);
+}
+
+#[test]
+fn test_default_listeners() {
+    let config = Config::parse("[tls]\ncert = \"a.crt\"\nkey = \"a.key\"\nport = 6698\n").unwrap();
+    let listeners = config.listeners();
+
+    assert_eq!(2, listeners.len());
+    assert_eq!(Some("0.0.0.0:6667".parse().unwrap()), listeners[0].address);
+    assert!(!listeners[0].tls);
+    assert!(listeners[1].tls);
+    assert_eq!(Some(6698), config.sts_port());
+}
+
+#[test]
+fn test_invalid_listeners() {
+    let tls = "[tls]\ncert = \"a.crt\"\nkey = \"a.key\"\n";
+    for listen in [
+        "[[listen]]\ntls = true\n",
+        "[[listen]]\naddress = \"0.0.0.0:6667\"\npath = \"irc.sock\"\n",
+        "[[listen]]\naddress = \"0.0.0.0:6667\"\nclass = \"missing\"\n",
+        "[[listen]]\npath = \"irc.sock\"\nproxy = [\"127.0.0.1\"]\n",
+    ] {
+        assert!(Config::parse(&format!("{}{}", tls, listen)).is_err());
+    }
+
+    assert!(Config::parse("[[listen]]\naddress = \"0.0.0.0:6697\"\ntls = true\n").is_err());
+}
+
+#[test]
+fn test_empty_config() {
+    let config = Config::parse("").unwrap();
+
+    assert!(config.opers.is_empty());
+    assert!(config.path.is_none());
+    assert!(config.ban_file.is_none());
+    assert_eq!(DEFAULT_SENDQ, config.sendq);
+    assert_eq!(Charset::Reject, config.charset);
+    assert!(config.tls.is_none());
+}
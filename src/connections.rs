@@ -2,63 +2,160 @@
 
 use std::{
     collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex}, fmt::format, time::Duration,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, MutexGuard, RwLock}, fmt::format, time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::UnboundedSender;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 
-use crate::{channels::Channels, errorcodes, messages::UserMessage, user::User};
-type ConnectionsMap = HashMap<SocketAddr, Sender<String>>;
-type NicksMap = HashMap<String, Sender<String>>;
+use crate::{
+    bans::{Ban, BanKind, BanList},
+    batch::{MultilineBuffer, MULTILINE_BATCH_TYPE, MULTILINE_CONCAT_TAG},
+    capabilities,
+    channels::{Channel, Channels, Membership},
+    charset::Charset,
+    config::{Config, ListenBlock},
+    elist::{self, ListFilter},
+    errorcodes,
+    flood::{self, FloodControl, FloodResult},
+    lookup::{self, Resolver, SystemResolver},
+    mask,
+    messages::{chunk_reply, find_tag, parse_tagged_message, Tag, UserMessage},
+    sendq::SendQ,
+    tls,
+    transport::{Peer, Transport},
+    user::User,
+    whowas::{WhowasEntry, WhowasHistory, WHOWAS_MAX_ENTRIES},
+};
+type ConnectionsMap = HashMap<u64, SendQ>;
+type NicksMap = HashMap<String, Client>;
 
 use anyhow::{anyhow, Context, Result};
 
 const HOST: &str = "172.17.0.1";
+const SERVER_INFO: &str = "AvalonIRC";
+const LIST_CHUNK_SIZE: usize = 64;
+
+#[derive(Clone)]
+pub struct Client {
+    pub sender: SendQ,
+    pub user: Arc<Mutex<User>>,
+    pub disconnect: UnboundedSender<String>,
+}
 
 #[derive(Clone)]
 pub struct Connections {
     pub connection_map: Arc<Mutex<ConnectionsMap>>,
     pub nicks_map: Arc<Mutex<NicksMap>>,
     pub channels: Arc<tokio::sync::Mutex<Channels>>,
+    pub whowas: Arc<Mutex<WhowasHistory>>,
+    pub config: Arc<RwLock<Config>>,
+    pub bans: Arc<Mutex<BanList>>,
+    pub tls: Arc<RwLock<Option<tls::Acceptors>>>,
+    pub resolver: Arc<dyn Resolver>,
+    // Connections are told apart by id, as Unix socket peers have no
+    // address of their own.
+    next_id: Arc<AtomicU64>,
 }
 
 impl Connections {
     pub fn new() -> Connections {
+        Connections::from_parts(Config::default(), BanList::new(), None)
+    }
+
+    pub fn with_config(config: Config) -> Result<Connections> {
+        let bans = match &config.ban_file {
+            Some(path) => BanList::load(path)?,
+            None => BanList::new(),
+        };
+        let tls = config.tls.as_ref().map(tls::acceptors).transpose()?;
+
+        Ok(Connections::from_parts(config, bans, tls))
+    }
+
+    fn from_parts(config: Config, bans: BanList, tls: Option<tls::Acceptors>) -> Connections {
         Connections {
             connection_map: Arc::new(Mutex::new(HashMap::new())),
             nicks_map: Arc::new(Mutex::new(HashMap::new())),
             channels: Arc::new(tokio::sync::Mutex::new(Channels::new())),
+            whowas: Arc::new(Mutex::new(WhowasHistory::new(WHOWAS_MAX_ENTRIES))),
+            config: Arc::new(RwLock::new(config)),
+            bans: Arc::new(Mutex::new(bans)),
+            tls: Arc::new(RwLock::new(tls)),
+            resolver: Arc::new(SystemResolver),
+            next_id: Arc::new(AtomicU64::new(0)),
         }
     }
 
+    pub fn find_dline(&self, ip: IpAddr) -> Option<Ban> {
+        self.bans.lock().unwrap().find_dline(ip)
+    }
+
+    pub fn sendq_limit(&self, listen: &ListenBlock) -> usize {
+        self.config.read().unwrap().sendq_for(listen.class.as_deref())
+    }
+
     pub fn register_connection(
         &mut self,
-        address: SocketAddr,
-        sender: Sender<String>,
+        peer: Peer,
+        listen: &ListenBlock,
+        sender: SendQ,
+        disconnect: UnboundedSender<String>,
     ) -> Result<UserConnection> {
-        let mut map = self.connection_map.lock().unwrap();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let address = peer.addr;
+        self.connection_map.lock().unwrap().insert(id, sender.clone());
 
-        map.insert(address, sender.clone());
+        let mut user = User::new();
+        // Unix socket peers only get a placeholder address.
+        user.ip = (peer.transport != Transport::Unix).then(|| address.ip());
+        // Until a lookup or WEBIRC finds a name, the host is the address.
+        // The one the client gives in USER is never used.
+        user.host = Some(user.ip.map_or_else(|| "localhost".into(), ip_host));
+        user.secure = peer.secure;
+        user.certfp = peer.certfp;
 
-        return Ok(UserConnection {
+        let config = self.config.read().unwrap();
+        let flood = FloodControl::new(config.flood_for(listen.class.as_deref()));
+        let charset = listen.charset.unwrap_or(config.charset);
+        drop(config);
+
+        Ok(UserConnection {
             connections: self.clone(),
+            id,
             sender,
+            disconnect,
             address,
-            user: User::new(),
+            user: Arc::new(Mutex::new(user)),
             authenticated: false,
-        });
+            cap_negotiating: false,
+            multiline: None,
+            flood,
+            charset,
+            input_utf8: true,
+            sasl_external: false,
+            password_hash: listen.password.clone(),
+            password: None,
+            webirc: false,
+            ident: None,
+            host_verified: false,
+            closed: false,
+        })
     }
 
-    fn set_nick_if_available(&mut self, sender: Sender<String>, nick: &str) -> Result<bool> {
+    fn set_nick_if_available(&mut self, client: Client, nick: &str) -> Result<bool> {
         let mut map = self.nicks_map.lock().unwrap();
 
         if map.contains_key(nick) {
             return Ok(false);
         }
 
-        map.insert(nick.into(), sender);
-        return Ok(true);
+        map.insert(nick.into(), client);
+        Ok(true)
+    }
+
+    fn client(&self, nick: &str) -> Option<Client> {
+        self.nicks_map.lock().unwrap().get(nick).cloned()
     }
 
     async fn send_msg_to_nicks(
@@ -66,40 +163,124 @@ impl Connections {
         message_fn: impl Fn(&str) -> String,
         nicks: impl Iterator<Item = &str>,
     ) {
-        let senders = {
-            let mut senders = vec![];
+        self.send_msg_to_users(|nick, _| message_fn(nick), nicks)
+            .await;
+    }
+
+    async fn send_msg_to_users(
+        &mut self,
+        message_fn: impl Fn(&str, &User) -> String,
+        nicks: impl Iterator<Item = &str>,
+    ) {
+        let clients = {
+            let mut clients = vec![];
             let map = self.nicks_map.lock().unwrap();
 
             for nick in nicks {
-                if let Some(sender) = map.get(nick) {
-                    let sender = sender.clone();
-                    senders.push((nick, sender));
+                if let Some(client) = map.get(nick) {
+                    clients.push((nick, client.clone()));
                 }
             }
-            senders
+            clients
         };
 
-        for (nick, sender) in senders {
-            let message_to_send = message_fn(nick);
-            sender.send(message_to_send).await;
+        for (nick, client) in clients {
+            let message_to_send = message_fn(nick, &client.user.lock().unwrap());
+            let _ = client.sender.send(message_to_send);
         }
     }
 }
 
 pub struct UserConnection {
     connections: Connections,
+    id: u64,
     address: SocketAddr,
-    sender: Sender<String>,
-    user: User,
+    sender: SendQ,
+    disconnect: UnboundedSender<String>,
+    user: Arc<Mutex<User>>,
     authenticated: bool,
+    cap_negotiating: bool,
+    multiline: Option<MultilineBuffer>,
+    flood: FloodControl,
+    charset: Charset,
+    // Whether the line being handled arrived as valid UTF-8.
+    input_utf8: bool,
+    // An AUTHENTICATE EXTERNAL exchange is waiting for the client's response.
+    sasl_external: bool,
+    // What the listener requires with PASS, and what the client sent.
+    password_hash: Option<String>,
+    password: Option<String>,
+    // The address and host came from a WEBIRC gateway.
+    webirc: bool,
+    // The user name the ident server gave, which wins over USER.
+    ident: Option<String>,
+    // The host came from DNS or a WEBIRC gateway, not from USER.
+    host_verified: bool,
+    closed: bool,
 }
 
 impl UserConnection {
-    pub async fn handle_message<'a>(&mut self, message: &UserMessage<'a>) {
-        let _ = self.handle_message_aux(message).await;
+    pub async fn handle_line(&mut self, line: Vec<u8>) {
+        let Some(input) = self.charset.decode(line) else {
+            let nick = self.user().nick.clone().unwrap_or("*".into());
+            let _ = self.sender.send(format!(
+                ":{} NOTICE {} :Your message was dropped because it is not valid UTF-8\r\n",
+                HOST, nick
+            ));
+            return;
+        };
+
+        if input.text.trim().is_empty() {
+            return;
+        }
+        //println!("{} <-|{}|", self.address, input.text.trim());
+        self.input_utf8 = input.utf8;
+        let (tags, message) = parse_tagged_message(&input.text);
+        self.handle_message(&tags, &message).await;
+    }
+
+    pub async fn handle_message<'a>(&mut self, tags: &[Tag<'a>], message: &UserMessage<'a>) {
+        // Lines of an open multiline batch are bounded by its max-lines,
+        // and the BATCH that ends it is charged as one command.
+        if !self.in_multiline(tags) && !self.check_flood(message).await {
+            return;
+        }
+
+        let _ = self.handle_message_aux(tags, message).await;
+    }
+
+    // Returns false when the client was disconnected for flooding.
+    async fn check_flood(&mut self, message: &UserMessage<'_>) -> bool {
+        if self.user().is_oper() {
+            return true;
+        }
+
+        match self.flood.consume(flood::cost(message), Instant::now()) {
+            FloodResult::Allow => true,
+            FloodResult::Delay(delay) => {
+                tokio::time::sleep(delay).await;
+                true
+            }
+            FloodResult::ExcessFlood => {
+                let _ = self.disconnect("Excess Flood").await;
+                false
+            }
+        }
     }
 
-    async fn handle_message_aux<'a>(&mut self, message: &UserMessage<'a>) -> Result<()> {
+    async fn handle_message_aux<'a>(
+        &mut self,
+        tags: &[Tag<'a>],
+        message: &UserMessage<'a>,
+    ) -> Result<()> {
+        if let Some(Tag {
+            value: Some(reference),
+            ..
+        }) = find_tag(tags, "batch")
+        {
+            return self.add_to_multiline(reference, tags, message).await;
+        }
+
         match message {
             UserMessage::Nick {
                 nickname,
@@ -119,7 +300,7 @@ impl UserConnection {
             }
             UserMessage::Password { password } => self.set_password(password).await?,
             UserMessage::PrivateMessage { receivers, message } => {
-                self.send_priv_msg(receivers.iter().map(|s| *s), message)
+                self.send_priv_msg(receivers.iter().copied(), message)
                     .await?
             }
             UserMessage::MessageToChannel { channel, message } => {
@@ -128,24 +309,127 @@ impl UserConnection {
             UserMessage::Join { channels, keys } => self.join_channels(channels, keys).await?,
             UserMessage::Quit { quit_msg } => self.quit(*quit_msg).await?,
             UserMessage::Ping { server } => self.ping(server).await?,
-            UserMessage::Mode { channel, mode } => self.set_mode(channel, *mode).await?,
+            UserMessage::Mode { target, mode } => self.set_mode(target, *mode).await?,
+            UserMessage::Invite { nick, channel } => self.invite(nick, channel).await?,
+            UserMessage::Kick {
+                channels,
+                users,
+                reason,
+            } => self.kick(channels, users, *reason).await?,
+            UserMessage::Names { channels } => self.names(channels).await?,
+            UserMessage::List { params } => self.list(params).await?,
+            UserMessage::Who { mask, options } => self.who(*mask, *options).await?,
+            UserMessage::Whois { nicks } => self.whois(nicks).await?,
+            UserMessage::Whowas { nicks, count } => self.whowas(nicks, *count).await?,
+            UserMessage::Away { message } => self.away(*message).await?,
+            UserMessage::Oper { name, password } => self.oper(name, password).await?,
+            UserMessage::Kill { nick, reason } => self.kill(nick, reason).await?,
+            UserMessage::Wallops { message } => self.wallops(message).await?,
+            UserMessage::Rehash => self.rehash().await?,
+            UserMessage::Ban {
+                kind,
+                duration,
+                mask,
+                reason,
+            } => self.add_ban(*kind, *duration, mask, *reason).await?,
+            UserMessage::Unban { kind, mask } => self.remove_ban(*kind, mask).await?,
+            UserMessage::Cap { subcommand, args } => self.cap(subcommand, args).await?,
+            UserMessage::BatchStart {
+                reference,
+                batch_type,
+                params,
+            } => self.start_batch(reference, batch_type, params).await?,
+            UserMessage::BatchEnd { reference } => self.end_batch(reference).await?,
+            UserMessage::Authenticate { data } => self.authenticate(data).await?,
+            UserMessage::WebIrc {
+                password,
+                gateway: _,
+                hostname,
+                ip,
+                flags,
+            } => self.webirc(password, hostname, ip, flags).await?,
             UserMessage::InvalidMessage => {}
         }
 
         Ok(())
     }
 
+    fn user(&self) -> MutexGuard<'_, User> {
+        self.user.lock().unwrap()
+    }
+
+    fn nick(&self) -> Result<String> {
+        self.user().nick.clone().context("NICK is not set")
+    }
+
+    fn source(&self) -> Result<String> {
+        let user = self.user();
+        let nick = user.nick.as_ref().context("NICK is not set")?;
+        let user_name = user.user.as_ref().unwrap_or(nick);
+
+        Ok(format!("{}!{}@{}", nick, user_name, user.host()))
+    }
+
     async fn check_authenticated(&mut self) -> Result<()> {
-        if self.authenticated {
+        if self.authenticated || self.cap_negotiating {
             return Ok(());
         }
 
-        if let (Some(nick), Some(_)) = (&self.user.nick, &self.user.user) {
+        let nick = {
+            let user = self.user();
+            match (&user.nick, &user.user) {
+                (Some(nick), Some(_)) => Some(nick.clone()),
+                _ => None,
+            }
+        };
+
+        if let Some(nick) = nick {
+            if let Some(hash) = self.password_hash.clone() {
+                // bcrypt is slow on purpose, so it runs on the blocking pool
+                // rather than hold up the other clients on this thread.
+                let password = self.password.clone();
+                let matches = tokio::task::spawn_blocking(move || {
+                    password.is_some_and(|password| bcrypt::verify(password, &hash).unwrap_or(false))
+                })
+                .await?;
+                if !matches {
+                    self.sender.send(format!(
+                        ":{} {} {} :Password incorrect\r\n",
+                        HOST, errorcodes::ERR_PASSWDMISMATCH, nick
+                    ))?;
+                    return self.disconnect("Bad password").await;
+                }
+            }
+
+            // Copied out so that the user is unlocked by the time the
+            // configuration and the bans are.
+            let (user_name, host, ip, certfp) = {
+                let user = self.user();
+                (
+                    user.user.clone().unwrap_or_default(),
+                    user.host().to_string(),
+                    user.ip,
+                    user.certfp.clone(),
+                )
+            };
+            let kline = if self.connections.config.read().unwrap().is_kline_exempt(certfp.as_deref()) {
+                None
+            } else {
+                self.connections.bans.lock().unwrap().find_kline(&user_name, &host, ip)
+            };
+            if let Some(ban) = kline {
+                self.sender.send(format!(
+                    ":{} {} {} :You are banned from this server- {}\r\n",
+                    HOST, errorcodes::ERR_YOUREBANNEDCREEP, nick, ban.reason
+                ))?;
+                return self.disconnect(&format!("{}: {}", ban.kind.name(), ban.reason)).await;
+            }
+
             let welcome_msg = format!(
                 ":{} 001 {} :Welcome to the Internet Relay Network, {}!\r\n",
                 HOST, nick, nick
             );
-            self.sender.send(welcome_msg).await?;
+            self.sender.send(welcome_msg)?;
             self.authenticated = true;
         }
 
@@ -153,32 +437,94 @@ impl UserConnection {
     }
 
     async fn set_nick(&mut self, nickname: &str, _hop_count: usize) -> Result<()> {
-        let sender = self.sender.clone();
-        let result = self.connections.set_nick_if_available(sender, nickname)?;
+        let old_nick = self.user().nick.clone();
+        if old_nick.as_deref() == Some(nickname) {
+            return Ok(());
+        }
+
+        let client = Client {
+            sender: self.sender.clone(),
+            user: self.user.clone(),
+            disconnect: self.disconnect.clone(),
+        };
+        let result = self.connections.set_nick_if_available(client, nickname)?;
 
         if !result {
             let message = format!(
-                ":{} {} * {} :Nickname already in use\r\n",
+                ":{} {} {} {} :Nickname is already in use\r\n",
                 HOST,
-                errorcodes::ERR_ALREADYREGISTRED,
+                errorcodes::ERR_NICKNAMEINUSE,
+                old_nick.as_deref().unwrap_or("*"),
                 nickname
             );
-            self.sender.send(message).await?;
-        } else {
-            self.user.nick = Some(nickname.into());
+            self.sender.send(message)?;
+            return Ok(());
+        }
+
+        let Some(old_nick) = old_nick else {
+            self.user().nick = Some(nickname.into());
+            return Ok(());
+        };
+
+        self.connections.nicks_map.lock().unwrap().remove(&old_nick);
+
+        if !self.authenticated {
+            self.connections.channels.lock().await.rename_user(&old_nick, nickname);
+            self.user().nick = Some(nickname.into());
+            return Ok(());
         }
 
+        let source = self.source()?;
+        self.record_whowas(&old_nick);
+
+        let oclone = self.connections.channels.clone();
+        let mut channels = oclone.lock().await;
+        let peers = channels.peers(&old_nick);
+        channels.rename_user(&old_nick, nickname);
+        self.user().nick = Some(nickname.into());
+
+        let nick_msg = format!(":{} NICK :{}\r\n", source, nickname);
+        self.connections
+            .send_msg_to_nicks(|_| nick_msg.clone(), peers.iter().map(|s| s.as_str()))
+            .await;
+        self.sender.send(nick_msg.clone())?;
+
         Ok(())
     }
 
-    fn set_user(&mut self, user_name: &str, host_name: &str, _server_name: &str, real_name: &str) {
-        self.user.user = Some(user_name.into());
-        self.user.host = Some(host_name.into());
-        self.user.full_name = Some(real_name.into());
+    fn record_whowas(&self, nick: &str) {
+        let entry = {
+            let user = self.user();
+            WhowasEntry {
+                nick: nick.into(),
+                user: user.user.clone().unwrap_or_default(),
+                host: user.host().into(),
+                full_name: user.full_name.clone().unwrap_or_default(),
+                time: SystemTime::now(),
+            }
+        };
+
+        self.connections.whowas.lock().unwrap().record(entry);
+    }
+
+    fn set_user(&mut self, user_name: &str, _host_name: &str, _server_name: &str, real_name: &str) {
+        let mut user = self.user();
+        user.user = Some(self.ident.clone().unwrap_or(user_name.into()));
+        user.full_name = Some(real_name.into());
     }
 
-    async fn set_password(&self, password: &str) -> Result<()> {
-        todo!()
+    async fn set_password(&mut self, password: &str) -> Result<()> {
+        if self.authenticated {
+            let nick = self.nick()?;
+            self.sender.send(format!(
+                ":{} {} {} :You may not reregister\r\n",
+                HOST, errorcodes::ERR_ALREADYREGISTRED, nick
+            ))?;
+            return Ok(());
+        }
+
+        self.password = Some(password.to_string());
+        Ok(())
     }
 
     async fn send_priv_msg(
@@ -186,38 +532,50 @@ impl UserConnection {
         receivers: impl Iterator<Item = &str>,
         message: &str,
     ) -> Result<()> {
-        let sender = format!(
-            ":{}!{}@{}",
-            self.user.nick.as_ref().unwrap(),
-            self.user.user.as_ref().unwrap(),
-            self.address.to_string()
-        );
+        let sender = self.source()?;
+        let own_nick = self.nick()?;
+        self.user().last_active = Instant::now();
+        let receivers = receivers.collect::<Vec<_>>();
 
-        let message_fn = |nick: &'_ str| format!("{} PRIVMSG {} {}\r\n", &sender, nick, message);
+        let message_fn = |nick: &'_ str| format!(":{} PRIVMSG {} {}\r\n", &sender, nick, message);
 
         self.connections
-            .send_msg_to_nicks(message_fn, receivers)
+            .send_msg_to_nicks(message_fn, receivers.iter().copied())
             .await;
 
+        for receiver in receivers {
+            let away = self
+                .connections
+                .client(receiver)
+                .and_then(|client| client.user.lock().unwrap().away.clone());
+
+            if let Some(away) = away {
+                self.sender.send(format!(
+                    ":{} {} {} {} :{}\r\n",
+                    HOST, errorcodes::RPL_AWAY, own_nick, receiver, away
+                ))?;
+            }
+        }
+
         Ok(())
     }
 
     async fn send_msg_to_channel(&mut self, channel: &str, message: &str) -> Result<()> {
         let oclone = self.connections.channels.clone();
         let mut channels = oclone.lock().await;
-        let nick = self.user.nick.as_ref().context("NICK is not set")?.clone();
-        let nicks = channels.channel_list(channel).filter(|s| **s != *nick);
+        let nick = self.nick()?;
+        let sender = self.source()?;
 
-        let message_fn = |nick: &'_ str| {
-            let sender = format!(
-                ":{}!{}@{}",
-                self.user.nick.as_ref().unwrap(),
-                self.user.nick.as_ref().unwrap(),
-                self.address.to_string()
-            );
+        if !self.input_utf8 && channels.get(channel).is_some_and(|c| c.is_utf8_only()) {
+            self.sender.send(cannot_send_utf8(&nick, channel))?;
+            return Ok(());
+        }
 
-            format!("{} PRIVMSG {} :{}\r\n", &sender, channel, message)
-        };
+        let nicks = channels.channel_list(channel).filter(|s| **s != *nick);
+        self.user().last_active = Instant::now();
+
+        let message_fn =
+            |nick: &'_ str| format!(":{} PRIVMSG {} {}\r\n", &sender, channel, message);
 
         self.connections
             .send_msg_to_nicks(message_fn, nicks.map(|s| s.as_str()))
@@ -228,67 +586,1451 @@ impl UserConnection {
     async fn join_channels(&mut self, channels_names: &[&str], keys: &[&str]) -> Result<()> {
         let oclone = self.connections.channels.clone();
         let mut channels = oclone.lock().await;
-        let nick = self.user.nick.as_ref().context("NICK is not set")?.clone();
+        let nick = self.nick()?;
+        let sender = self.source()?;
+        let secure = self.user().secure;
 
         for channel_name in channels_names {
-            channels.join_user(channel_name, &nick);
-            let nicks = channels.channel_list(channel_name);
-
-            let mut nicks_list = String::new();
-            for n in channels.channel_list(channel_name) {
-                nicks_list.push_str(n);
-                nicks_list.push_str(" ");
+            let invite_only = channels
+                .get(channel_name)
+                .is_some_and(|chan| chan.is_invite_only() && !chan.invites.contains(&nick));
+            if invite_only {
+                self.sender.send(format!(
+                    ":{} {} {} {} :Cannot join channel (+i)\r\n",
+                    HOST, errorcodes::ERR_INVITEONLYCHAN, nick, channel_name
+                ))?;
+                continue;
+            }
+            if !secure && channels.get(channel_name).is_some_and(|chan| chan.is_secure_only()) {
+                self.sender.send(format!(
+                    ":{} {} {} {} :Cannot join channel (+z)\r\n",
+                    HOST, errorcodes::ERR_SECUREONLYCHAN, nick, channel_name
+                ))?;
+                continue;
             }
-            nicks_list.pop();
 
-            let sender = format!(
-                ":{}!{}@{}",
-                self.user.nick.as_ref().unwrap(),
-                self.user.nick.as_ref().unwrap(),
-                self.address.to_string()
-            );
+            channels.join_user(channel_name, &nick);
+            let nicks = channels.channel_list(channel_name);
 
             let message_fn = |nick: &'_ str| {
-                format!("{} JOIN :{}\r\n", sender, channel_name)
+                format!(":{} JOIN :{}\r\n", sender, channel_name)
             };
             self.connections
-                .send_msg_to_nicks(message_fn,  
+                .send_msg_to_nicks(message_fn,
                     nicks
                     .filter(|s| **s != *nick)
                     .map(|s| s.as_str()))
-                .await;   
+                .await;
 
-            let response = format!(
-                "{} JOIN :{}\r\n:{} 353 {} = {} :{}\r\n:{} 366 {} {} :End of /NAMES list.\r\n",
-                &sender, channel_name,
-                HOST, nick, channel_name, nicks_list,
-                HOST, nick, channel_name
-            );
-            self.sender.send(response).await?;
+            let mut response = format!(":{} JOIN :{}\r\n", &sender, channel_name);
+            if let Some(chan) = channels.get(channel_name) {
+                response.push_str(&self.names_reply(&nick, channel_name, chan));
+            }
+            response.push_str(&format!(
+                ":{} {} {} {} :End of /NAMES list.\r\n",
+                HOST, errorcodes::RPL_ENDOFNAMES, nick, channel_name
+            ));
+            self.sender.send(response)?;
+        }
+
+        Ok(())
+    }
+
+    async fn kick(&mut self, channels_names: &[&str], users: &[&str], reason: Option<&str>) -> Result<()> {
+        let me = self.nick()?;
+        let source = self.source()?;
+        let reason = reason.unwrap_or(&me);
+
+        // One channel applies to every user; otherwise channels and users pair up.
+        let targets = users.iter().enumerate().map(|(i, user)| {
+            let channel = if channels_names.len() == 1 {
+                channels_names[0]
+            } else {
+                channels_names.get(i).copied().unwrap_or("")
+            };
+            (channel, *user)
+        });
+
+        let oclone = self.connections.channels.clone();
+        let mut channels = oclone.lock().await;
+
+        for (channel_name, target) in targets {
+            let error = match channels.get(channel_name) {
+                None => Some((
+                    errorcodes::ERR_NOSUCHCHANNEL,
+                    format!("{} :No such channel", channel_name),
+                )),
+                Some(chan) => match chan.members.get(&me) {
+                    None => Some((
+                        errorcodes::ERR_NOTONCHANNEL,
+                        format!("{} :You're not on that channel", channel_name),
+                    )),
+                    Some(membership) if !membership.op => Some((
+                        errorcodes::ERR_CHANOPRIVSNEEDED,
+                        format!("{} :You're not channel operator", channel_name),
+                    )),
+                    Some(_) if !chan.is_member(target) => Some((
+                        errorcodes::ERR_USERNOTINCHANNEL,
+                        format!("{} {} :They aren't on that channel", target, channel_name),
+                    )),
+                    Some(_) => None,
+                },
+            };
+
+            if let Some((code, error)) = error {
+                self.sender
+                    .send(format!(":{} {} {} {}\r\n", HOST, code, me, error))?;
+                continue;
+            }
+
+            let kick_msg = format!(":{} KICK {} {} :{}\r\n", source, channel_name, target, reason);
+            let members = channels.channel_list(channel_name).cloned().collect::<Vec<_>>();
+            self.connections
+                .send_msg_to_nicks(|_| kick_msg.clone(), members.iter().map(|s| s.as_str()))
+                .await;
+
+            channels.part_user(channel_name, target);
+        }
+
+        Ok(())
+    }
+
+    fn names_reply(&self, me: &str, channel_name: &str, chan: &Channel) -> String {
+        let is_member = chan.is_member(me);
+        if chan.is_secret() && !is_member {
+            return String::new();
+        }
+
+        let names = chan
+            .members
+            .iter()
+            .filter(|(nick, _)| {
+                is_member
+                    || !self
+                        .connections
+                        .client(nick)
+                        .is_some_and(|client| client.user.lock().unwrap().is_invisible())
+            })
+            .map(|(nick, membership)| format!("{}{}", membership.prefix(), nick))
+            .collect::<Vec<_>>();
+
+        let prefix = format!(
+            ":{} {} {} {} {}",
+            HOST, errorcodes::RPL_NAMREPLY, me, chan.symbol(), channel_name
+        );
+        chunk_reply(&prefix, names.iter().map(|n| n.as_str()))
+    }
+
+    async fn names(&mut self, channels_names: &[&str]) -> Result<()> {
+        let me = self.nick()?;
+        let channels = self.connections.channels.lock().await;
+        let mut reply = String::new();
+
+        if channels_names.is_empty() {
+            for name in channels.channel_names() {
+                if let Some(chan) = channels.get(&name) {
+                    reply.push_str(&self.names_reply(&me, &name, chan));
+                }
+            }
+            reply.push_str(&format!(
+                ":{} {} {} * :End of /NAMES list.\r\n",
+                HOST, errorcodes::RPL_ENDOFNAMES, me
+            ));
+        }
+
+        for name in channels_names {
+            if let Some(chan) = channels.get(name) {
+                reply.push_str(&self.names_reply(&me, name, chan));
+            }
+            reply.push_str(&format!(
+                ":{} {} {} {} :End of /NAMES list.\r\n",
+                HOST, errorcodes::RPL_ENDOFNAMES, me, name
+            ));
         }
+        drop(channels);
+
+        self.sender.send(reply)?;
+        Ok(())
+    }
+
+    async fn quit(&mut self, quit_msg: Option<&str>) -> Result<()> {
+        let reason = match quit_msg {
+            Some(msg) => format!("Quit: {}", msg),
+            None => "Client Quit".into(),
+        };
+
+        self.disconnect(&reason).await
+    }
+
+    pub async fn input_too_long(&mut self) -> Result<()> {
+        let nick = self.user().nick.clone().unwrap_or("*".into());
+        self.sender.send(format!(
+            ":{} {} {} :Input line was too long\r\n",
+            HOST, errorcodes::ERR_INPUTTOOLONG, nick
+        ))?;
 
         Ok(())
     }
 
-    async fn quit(&self, quit_msg: Option<&str>) -> Result<()> {
-        todo!()
+    // Whether the client negotiated a capability that lets it send tags,
+    // which gives its lines the extra tag budget.
+    pub fn client_tags(&self) -> bool {
+        self.user().has_cap(capabilities::MULTILINE)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // Runs before anything the client sends is handled, so registration
+    // waits for the lookups. Both run at once, each under the timeout.
+    pub async fn look_up(&mut self, local: Option<SocketAddr>) {
+        let config = self.connections.config.read().unwrap().lookup.clone();
+        let resolver = self.connections.resolver.clone();
+        let ip = self.user().ip;
+        let limit = Duration::from_secs(config.timeout);
+        let notice = |text: &str| {
+            let _ = self
+                .sender
+                .send(format!(":{} NOTICE AUTH :*** {}\r\n", HOST, text));
+        };
+
+        let dns = async {
+            let ip = ip.filter(|_| config.dns)?;
+            notice("Looking up your hostname...");
+            let host = tokio::time::timeout(limit, lookup::verified_host(&*resolver, ip))
+                .await
+                .ok()
+                .flatten();
+            match host {
+                Some(_) => notice("Found your hostname"),
+                None => notice("Couldn't look up your hostname"),
+            }
+            host
+        };
+        let remote = self.address;
+        let ident = async {
+            let local = local.filter(|_| config.ident)?;
+            notice("Checking Ident");
+            let user = tokio::time::timeout(limit, resolver.ident(remote, local))
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .and_then(|reply| lookup::parse_ident_reply(&reply, remote.port(), local.port()));
+            match user {
+                Some(_) => notice("Got Ident response"),
+                None => notice("No Ident response"),
+            }
+            user
+        };
+        let (host, ident) = tokio::join!(dns, ident);
+
+        if let Some(host) = host {
+            self.host_verified = true;
+            self.user().host = Some(host);
+        }
+        self.ident = ident;
+    }
+
+    pub async fn disconnect(&mut self, reason: &str) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+
+        self.connections
+            .connection_map
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+
+        let nick = self.user().nick.clone();
+        if let Some(nick) = nick {
+            self.connections.nicks_map.lock().unwrap().remove(&nick);
+
+            if self.authenticated {
+                let source = self.source()?;
+                self.record_whowas(&nick);
+
+                let oclone = self.connections.channels.clone();
+                let mut channels = oclone.lock().await;
+                let peers = channels.peers(&nick);
+                channels.remove_user(&nick);
+
+                let quit_msg = format!(":{} QUIT :{}\r\n", source, reason);
+                self.connections
+                    .send_msg_to_nicks(|_| quit_msg.clone(), peers.iter().map(|s| s.as_str()))
+                    .await;
+            } else {
+                self.connections.channels.lock().await.remove_user(&nick);
+            }
+        }
+
+        self.sender
+            .close(format!("ERROR :Closing link: {} ({})\r\n", HOST, reason))?;
+
+        Ok(())
     }
 
     async fn ping(&self, server: &str) -> Result<()> {
         let pong = format!(":{} {} {}\r\n", HOST, HOST, server);
-        self.sender.send(pong).await?;
+        self.sender.send(pong)?;
+
+        Ok(())
+    }
+
+    async fn set_mode(&mut self, channel: &str, mode: Option<&str>) -> Result<()> {
+        let nick = self.nick()?;
+
+        if !channel.starts_with('#') {
+            return self.set_user_mode(channel, mode).await;
+        }
+
+        let oclone = self.connections.channels.clone();
+        let mut channels = oclone.lock().await;
+
+        let Some(chan) = channels.get_mut(channel) else {
+            self.sender.send(format!(
+                ":{} {} {} {} :No such channel\r\n",
+                HOST, errorcodes::ERR_NOSUCHCHANNEL, nick, channel
+            ))?;
+            return Ok(());
+        };
+
+        let Some(mode) = mode else {
+            let modes = chan.mode_string();
+            self.sender.send(format!(
+                ":{} {} {} {} {}\r\n",
+                HOST, errorcodes::RPL_CHANNELMODEIS, nick, channel, modes
+            ))?;
+            return Ok(());
+        };
+
+        if !chan.members.get(&nick).is_some_and(|m| m.op) {
+            self.sender.send(format!(
+                ":{} {} {} {} :You're not channel operator\r\n",
+                HOST, errorcodes::ERR_CHANOPRIVSNEEDED, nick, channel
+            ))?;
+            return Ok(());
+        }
+
+        let (applied, unknown) = chan.apply_modes(mode);
+        let members = chan.members.keys().cloned().collect::<Vec<_>>();
+        drop(channels);
+
+        for c in unknown {
+            self.sender.send(format!(
+                ":{} {} {} {} :is unknown mode char to me\r\n",
+                HOST, errorcodes::ERR_UNKNOWNMODE, nick, c
+            ))?;
+        }
+
+        if !applied.is_empty() {
+            let mode_msg = format!(":{} MODE {} {}\r\n", self.source()?, channel, applied);
+            self.connections
+                .send_msg_to_nicks(|_| mode_msg.clone(), members.iter().map(|s| s.as_str()))
+                .await;
+        }
+
+        Ok(())
+    }
+
+    async fn set_user_mode(&mut self, target: &str, mode: Option<&str>) -> Result<()> {
+        let nick = self.nick()?;
+
+        if target != nick {
+            self.sender.send(format!(
+                ":{} {} {} :Cant change mode for other users\r\n",
+                HOST, errorcodes::ERR_USERSDONTMATCH, nick
+            ))?;
+            return Ok(());
+        }
+
+        let Some(mode) = mode else {
+            let modes = self.user().mode_string();
+            self.sender.send(format!(
+                ":{} {} {} {}\r\n",
+                HOST, errorcodes::RPL_UMODEIS, nick, modes
+            ))?;
+            return Ok(());
+        };
+
+        let (applied, unknown) = self.user().apply_modes(mode);
+
+        if unknown {
+            self.sender.send(format!(
+                ":{} {} {} :Unknown MODE flag\r\n",
+                HOST, errorcodes::ERR_UMODEUNKNOWNFLAG, nick
+            ))?;
+        }
+        if !applied.is_empty() {
+            self.sender
+                .send(format!(":{} MODE {} :{}\r\n", nick, nick, applied))?;
+        }
+
+        Ok(())
+    }
+
+    async fn invite(&mut self, target: &str, channel_name: &str) -> Result<()> {
+        let me = self.nick()?;
+        let source = self.source()?;
+
+        let Some(client) = self.connections.client(target) else {
+            self.sender.send(format!(
+                ":{} {} {} {} :No such nick/channel\r\n",
+                HOST, errorcodes::ERR_NOSUCHNICK, me, target
+            ))?;
+            return Ok(());
+        };
+
+        let oclone = self.connections.channels.clone();
+        let mut channels = oclone.lock().await;
+        let mut notify = vec![];
+
+        if let Some(chan) = channels.get_mut(channel_name) {
+            let error = match chan.members.get(&me) {
+                None => Some((
+                    errorcodes::ERR_NOTONCHANNEL,
+                    format!("{} :You're not on that channel", channel_name),
+                )),
+                Some(membership) if chan.is_invite_only() && !membership.op => Some((
+                    errorcodes::ERR_CHANOPRIVSNEEDED,
+                    format!("{} :You're not channel operator", channel_name),
+                )),
+                Some(_) if chan.is_member(target) => Some((
+                    errorcodes::ERR_USERONCHANNEL,
+                    format!("{} {} :is already on channel", target, channel_name),
+                )),
+                Some(_) => None,
+            };
+
+            if let Some((code, error)) = error {
+                self.sender
+                    .send(format!(":{} {} {} {}\r\n", HOST, code, me, error))?;
+                return Ok(());
+            }
+
+            chan.invites.insert(target.into());
+            notify = chan
+                .members
+                .iter()
+                .filter(|(nick, membership)| membership.op && **nick != me)
+                .map(|(nick, _)| nick.clone())
+                .filter(|nick| {
+                    self.connections.client(nick).is_some_and(|client| {
+                        client.user.lock().unwrap().has_cap(capabilities::INVITE_NOTIFY)
+                    })
+                })
+                .collect();
+        }
+        drop(channels);
+
+        let away = client.user.lock().unwrap().away.clone();
+        self.sender.send(format!(
+            ":{} {} {} {} {}\r\n",
+            HOST, errorcodes::RPL_INVITING, me, target, channel_name
+        ))?;
+        if let Some(away) = away {
+            self.sender.send(format!(
+                ":{} {} {} {} :{}\r\n",
+                HOST, errorcodes::RPL_AWAY, me, target, away
+            ))?;
+        }
+
+        let invite_msg = format!(":{} INVITE {} :{}\r\n", source, target, channel_name);
+        let _ = client.sender.send(invite_msg);
+
+        let notify_msg = format!(":{} INVITE {} {}\r\n", source, target, channel_name);
+        self.connections
+            .send_msg_to_nicks(|_| notify_msg.clone(), notify.iter().map(|s| s.as_str()))
+            .await;
 
         Ok(())
     }
 
-    async fn set_mode(&self, channel: &str, mode: Option<&str>) -> Result<()> {
-        let nick = self.user.nick.as_ref().context("NICK is not set")?.clone();
+    async fn list(&mut self, params: &[&str]) -> Result<()> {
+        let me = self.nick()?;
+        let filters = params
+            .iter()
+            .filter_map(|p| ListFilter::parse(p))
+            .collect::<Vec<_>>();
+
+        self.sender.send(format!(
+            ":{} {} {} Channel :Users  Name\r\n",
+            HOST, errorcodes::RPL_LISTSTART, me
+        ))?;
+
+        // Only a chunk of channels is looked at per lock, so a long listing
+        // does not keep everyone else from joining or talking meanwhile.
+        let names = self.connections.channels.lock().await.channel_names();
+        for chunk in names.chunks(LIST_CHUNK_SIZE) {
+            let mut reply = String::new();
+            {
+                let channels = self.connections.channels.lock().await;
+                for name in chunk {
+                    let Some(chan) = channels.get(name) else {
+                        continue;
+                    };
+                    if chan.is_secret() && !chan.is_member(&me) {
+                        continue;
+                    }
+
+                    let entry = chan.list_entry(name);
+                    if elist::matches_all(&filters, &entry) {
+                        reply.push_str(&format!(
+                            ":{} {} {} {} {} :{}\r\n",
+                            HOST, errorcodes::RPL_LIST, me, entry.name, entry.users, entry.topic
+                        ));
+                    }
+                }
+            }
+
+            if !reply.is_empty() {
+                self.sender.send(reply)?;
+            }
+        }
 
         self.sender.send(format!(
-            ":{} 324 {} {} +\r\n",
-            HOST, nick, channel
-        )).await?;
+            ":{} {} {} :End of /LIST\r\n",
+            HOST, errorcodes::RPL_LISTEND, me
+        ))?;
 
         Ok(())
     }
+
+    async fn who(&mut self, mask: Option<&str>, options: Option<&str>) -> Result<()> {
+        let me = self.nick()?;
+        let mask = mask.filter(|m| *m != "0").unwrap_or("*");
+        let options = options.unwrap_or("");
+        let (flags, whox) = match options.split_once('%') {
+            Some((flags, fields)) => (flags, Some(fields)),
+            None => (options, None),
+        };
+        let opers_only = flags.contains('o');
+        // Only opers see the address of others.
+        let oper = self.user().is_oper();
+
+        let mut reply = String::new();
+        let channels = self.connections.channels.lock().await;
+
+        if mask.starts_with('#') {
+            if let Some(chan) = channels.get(mask) {
+                let is_member = chan.is_member(&me);
+
+                if is_member || !chan.is_secret() {
+                    for (nick, membership) in &chan.members {
+                        let Some(client) = self.connections.client(nick) else {
+                            continue;
+                        };
+                        let user = client.user.lock().unwrap();
+
+                        if (!is_member && user.is_invisible()) || (opers_only && !user.is_oper()) {
+                            continue;
+                        }
+                        let show_ip = oper || *nick == me;
+                        reply.push_str(&who_reply(&me, mask, nick, &user, Some(membership), whox, show_ip));
+                    }
+                }
+            }
+        } else {
+            let peers = channels.peers(&me);
+            let clients = self
+                .connections
+                .nicks_map
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(nick, client)| (nick.clone(), client.clone()))
+                .collect::<Vec<_>>();
+
+            for (nick, client) in clients {
+                let user = client.user.lock().unwrap();
+
+                if user.is_invisible() && nick != me && !peers.contains(&nick) {
+                    continue;
+                }
+                if opers_only && !user.is_oper() {
+                    continue;
+                }
+
+                let candidates = [
+                    Some(nick.as_str()),
+                    user.user.as_deref(),
+                    Some(user.host()),
+                    user.full_name.as_deref(),
+                    Some(HOST),
+                ];
+                if !candidates.iter().flatten().any(|c| mask::matches(mask, c)) {
+                    continue;
+                }
+
+                let show_ip = oper || nick == me;
+                reply.push_str(&who_reply(&me, "*", &nick, &user, None, whox, show_ip));
+            }
+        }
+        drop(channels);
+
+        reply.push_str(&format!(
+            ":{} {} {} {} :End of WHO list\r\n",
+            HOST, errorcodes::RPL_ENDOFWHO, me, mask
+        ));
+        self.sender.send(reply)?;
+
+        Ok(())
+    }
+
+    async fn whois(&mut self, nicks: &[&str]) -> Result<()> {
+        let me = self.nick()?;
+
+        if nicks.is_empty() {
+            self.sender.send(format!(
+                ":{} {} {} :No nickname given\r\n",
+                HOST, errorcodes::ERR_NONICKNAMEGIVEN, me
+            ))?;
+            return Ok(());
+        }
+
+        let is_oper = self.user().is_oper();
+
+        for target in nicks {
+            let Some(client) = self.connections.client(target) else {
+                self.sender.send(format!(
+                    ":{} {} {} {} :No such nick/channel\r\n:{} {} {} {} :End of /WHOIS list.\r\n",
+                    HOST, errorcodes::ERR_NOSUCHNICK, me, target,
+                    HOST, errorcodes::RPL_ENDOFWHOIS, me, target
+                ))?;
+                continue;
+            };
+
+            let channel_list = {
+                let channels = self.connections.channels.lock().await;
+                let mut list = vec![];
+
+                for name in channels.user_channels(target) {
+                    if let Some(chan) = channels.get(&name) {
+                        if chan.is_secret() && !chan.is_member(&me) {
+                            continue;
+                        }
+                        list.push(format!("{}{}", chan.members[*target].prefix(), name));
+                    }
+                }
+                list.join(" ")
+            };
+
+            let mut reply = String::new();
+            {
+                let user = client.user.lock().unwrap();
+
+                reply.push_str(&format!(
+                    ":{} {} {} {} {} {} * :{}\r\n",
+                    HOST, errorcodes::RPL_WHOISUSER, me, target,
+                    user.user.as_deref().unwrap_or("*"),
+                    user.host(),
+                    user.full_name.as_deref().unwrap_or("")
+                ));
+                if !channel_list.is_empty() {
+                    reply.push_str(&format!(
+                        ":{} {} {} {} :{}\r\n",
+                        HOST, errorcodes::RPL_WHOISCHANNELS, me, target, channel_list
+                    ));
+                }
+                reply.push_str(&format!(
+                    ":{} {} {} {} {} :{}\r\n",
+                    HOST, errorcodes::RPL_WHOISSERVER, me, target, HOST, SERVER_INFO
+                ));
+                if user.is_oper() {
+                    reply.push_str(&format!(
+                        ":{} {} {} {} :is an IRC operator\r\n",
+                        HOST, errorcodes::RPL_WHOISOPERATOR, me, target
+                    ));
+                }
+                if let Some(away) = &user.away {
+                    reply.push_str(&format!(
+                        ":{} {} {} {} :{}\r\n",
+                        HOST, errorcodes::RPL_AWAY, me, target, away
+                    ));
+                }
+                let signon = user
+                    .signon
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                reply.push_str(&format!(
+                    ":{} {} {} {} {} {} :seconds idle, signon time\r\n",
+                    HOST, errorcodes::RPL_WHOISIDLE, me, target,
+                    user.last_active.elapsed().as_secs(), signon
+                ));
+                if let Some(account) = &user.account {
+                    reply.push_str(&format!(
+                        ":{} {} {} {} {} :is logged in as\r\n",
+                        HOST, errorcodes::RPL_WHOISACCOUNT, me, target, account
+                    ));
+                }
+                if user.secure {
+                    reply.push_str(&format!(
+                        ":{} {} {} {} :is using a secure connection\r\n",
+                        HOST, errorcodes::RPL_WHOISSECURE, me, target
+                    ));
+                }
+                // Only the user and operators get to see the fingerprint.
+                match &user.certfp {
+                    Some(certfp) if is_oper || me == *target => {
+                        reply.push_str(&format!(
+                            ":{} {} {} {} :has client certificate fingerprint {}\r\n",
+                            HOST, errorcodes::RPL_WHOISCERTFP, me, target, certfp
+                        ));
+                    }
+                    _ => {}
+                }
+                reply.push_str(&format!(
+                    ":{} {} {} {} :End of /WHOIS list.\r\n",
+                    HOST, errorcodes::RPL_ENDOFWHOIS, me, target
+                ));
+            }
+
+            self.sender.send(reply)?;
+        }
+
+        Ok(())
+    }
+
+    async fn whowas(&mut self, nicks: &[&str], count: Option<usize>) -> Result<()> {
+        let me = self.nick()?;
+
+        if nicks.is_empty() {
+            self.sender.send(format!(
+                ":{} {} {} :No nickname given\r\n",
+                HOST, errorcodes::ERR_NONICKNAMEGIVEN, me
+            ))?;
+            return Ok(());
+        }
+
+        for target in nicks {
+            let mut reply = String::new();
+            {
+                let history = self.connections.whowas.lock().unwrap();
+                let entries = history.lookup(target, count);
+
+                if entries.is_empty() {
+                    reply.push_str(&format!(
+                        ":{} {} {} {} :There was no such nickname\r\n",
+                        HOST, errorcodes::ERR_WASNOSUCHNICK, me, target
+                    ));
+                }
+
+                for entry in entries {
+                    let time = entry
+                        .time
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    reply.push_str(&format!(
+                        ":{} {} {} {} {} {} * :{}\r\n:{} {} {} {} {} :{}\r\n",
+                        HOST, errorcodes::RPL_WHOWASUSER, me, entry.nick,
+                        entry.user, entry.host, entry.full_name,
+                        HOST, errorcodes::RPL_WHOISSERVER, me, entry.nick, HOST, time
+                    ));
+                }
+            }
+            reply.push_str(&format!(
+                ":{} {} {} {} :End of WHOWAS\r\n",
+                HOST, errorcodes::RPL_ENDOFWHOWAS, me, target
+            ));
+
+            self.sender.send(reply)?;
+        }
+
+        Ok(())
+    }
+
+    async fn away(&mut self, message: Option<&str>) -> Result<()> {
+        let nick = self.nick()?;
+        self.user().away = message.map(|m| m.to_string());
+
+        let reply = match message {
+            Some(_) => format!(
+                ":{} {} {} :You have been marked as being away\r\n",
+                HOST, errorcodes::RPL_NOWAWAY, nick
+            ),
+            None => format!(
+                ":{} {} {} :You are no longer marked as being away\r\n",
+                HOST, errorcodes::RPL_UNAWAY, nick
+            ),
+        };
+        self.sender.send(reply)?;
+
+        Ok(())
+    }
+
+    async fn oper(&mut self, name: &str, password: &str) -> Result<()> {
+        let nick = self.nick()?;
+        let (user_name, host, ip, certfp) = {
+            let user = self.user();
+            (
+                user.user.clone().unwrap_or_default(),
+                user.host().to_string(),
+                user.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                user.certfp.clone(),
+            )
+        };
+
+        let result = {
+            let config = self.connections.config.read().unwrap();
+            match config.find_oper(name) {
+                None => Err(errorcodes::ERR_PASSWDMISMATCH),
+                Some(oper) => {
+                    // The host from USER is whatever the client says.
+                    let host_allowed = oper.hosts.iter().any(|mask| {
+                        (self.host_verified && mask::matches(mask, &format!("{}@{}", user_name, host)))
+                            || mask::matches(mask, &format!("{}@{}", user_name, ip))
+                    });
+
+                    if !host_allowed || !oper.check_certfp(certfp.as_deref()) {
+                        Err(errorcodes::ERR_NOOPERHOST)
+                    } else {
+                        Ok((oper.clone(), config.privileges(&oper.class)))
+                    }
+                }
+            }
+        };
+        let result = match result {
+            Ok((oper, privileges)) => {
+                let password = password.to_owned();
+                if tokio::task::spawn_blocking(move || oper.check_password(&password)).await? {
+                    Ok(privileges)
+                } else {
+                    Err(errorcodes::ERR_PASSWDMISMATCH)
+                }
+            }
+            Err(code) => Err(code),
+        };
+
+        match result {
+            Err(code) => {
+                let text = if code == errorcodes::ERR_NOOPERHOST {
+                    "No O-lines for your host"
+                } else {
+                    "Password incorrect"
+                };
+                self.sender
+                    .send(format!(":{} {} {} :{}\r\n", HOST, code, nick, text))?;
+            }
+            Ok(privileges) => {
+                {
+                    let mut user = self.user();
+                    user.modes.insert('o');
+                    user.privileges = privileges.into_iter().collect();
+                }
+                self.sender.send(format!(
+                    ":{} MODE {} :+o\r\n:{} {} {} :You are now an IRC operator\r\n",
+                    nick, nick, HOST, errorcodes::RPL_YOUREOPER, nick
+                ))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_privilege(&self, privilege: &str) -> Result<bool> {
+        if self.user().has_privilege(privilege) {
+            return Ok(true);
+        }
+
+        let nick = self.nick()?;
+        self.sender.send(format!(
+            ":{} {} {} :Permission Denied- You're not an IRC operator\r\n",
+            HOST, errorcodes::ERR_NOPRIVILEGES, nick
+        ))?;
+
+        Ok(false)
+    }
+
+    async fn kill(&mut self, target: &str, reason: &str) -> Result<()> {
+        if !self.check_privilege("kill").await? {
+            return Ok(());
+        }
+
+        let nick = self.nick()?;
+        let Some(client) = self.connections.client(target) else {
+            self.sender.send(format!(
+                ":{} {} {} {} :No such nick/channel\r\n",
+                HOST, errorcodes::ERR_NOSUCHNICK, nick, target
+            ))?;
+            return Ok(());
+        };
+
+        let _ = client
+            .sender
+            .send(format!(":{} KILL {} :{}\r\n", self.source()?, target, reason));
+        let _ = client
+            .disconnect
+            .send(format!("Killed ({} ({}))", nick, reason));
+
+        Ok(())
+    }
+
+    async fn wallops(&mut self, message: &str) -> Result<()> {
+        if !self.check_privilege("wallops").await? {
+            return Ok(());
+        }
+
+        let source = self.source()?;
+        let nicks = self
+            .connections
+            .nicks_map
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, client)| client.user.lock().unwrap().modes.contains(&'w'))
+            .map(|(nick, _)| nick.clone())
+            .collect::<Vec<_>>();
+
+        let wallops_msg = format!(":{} WALLOPS :{}\r\n", source, message);
+        self.connections
+            .send_msg_to_nicks(|_| wallops_msg.clone(), nicks.iter().map(|s| s.as_str()))
+            .await;
+
+        Ok(())
+    }
+
+    async fn rehash(&mut self) -> Result<()> {
+        if !self.check_privilege("rehash").await? {
+            return Ok(());
+        }
+
+        let nick = self.nick()?;
+        let path = self.connections.config.read().unwrap().path.clone();
+        let Some(path) = path else {
+            self.sender.send(format!(
+                ":{} NOTICE {} :No configuration file to rehash\r\n",
+                HOST, nick
+            ))?;
+            return Ok(());
+        };
+
+        self.sender.send(format!(
+            ":{} {} {} {} :Rehashing\r\n",
+            HOST, errorcodes::RPL_REHASHING, nick, path.display()
+        ))?;
+
+        match Config::load(&path) {
+            Ok(config) => {
+                // A certificate that fails to load keeps the previous one in use.
+                match config.tls.as_ref().map(tls::acceptors).transpose() {
+                    Ok(acceptors) => *self.connections.tls.write().unwrap() = acceptors,
+                    Err(e) => {
+                        self.sender.send(format!(
+                            ":{} NOTICE {} :TLS reload failed: {:#}\r\n",
+                            HOST, nick, e
+                        ))?;
+                    }
+                }
+                *self.connections.config.write().unwrap() = config;
+            }
+            Err(e) => {
+                self.sender.send(format!(
+                    ":{} NOTICE {} :Rehash failed: {}\r\n",
+                    HOST, nick, e
+                ))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn add_ban(
+        &mut self,
+        kind: BanKind,
+        duration: Option<Duration>,
+        mask: &str,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        if !self.check_privilege(kind.privilege()).await? {
+            return Ok(());
+        }
+
+        let nick = self.nick()?;
+        if kind != BanKind::D && !mask.contains('@') {
+            self.sender.send(format!(
+                ":{} NOTICE {} :Invalid {} mask {}, expected user@host\r\n",
+                HOST, nick, kind.name(), mask
+            ))?;
+            return Ok(());
+        }
+
+        let reason = reason.unwrap_or("No reason");
+        let ban = Ban::new(kind, mask, reason, &nick, duration);
+        let saved = self.connections.bans.lock().unwrap().add(ban.clone());
+
+        let notice = match (saved, duration) {
+            (Err(e), _) => format!("Added {} for [{}] but could not save it: {}", kind.name(), mask, e),
+            (Ok(()), Some(duration)) => format!(
+                "Added temporary {} {} min. for [{}] ({})",
+                kind.name(), duration.as_secs() / 60, mask, reason
+            ),
+            (Ok(()), None) => format!("Added {} for [{}] ({})", kind.name(), mask, reason),
+        };
+        self.sender
+            .send(format!(":{} NOTICE {} :{}\r\n", HOST, nick, notice))?;
+
+        // Users that are already connected are not checked again at
+        // registration, so drop the ones the new ban covers right away.
+        // No user is locked while the configuration is read.
+        let matched = self
+            .connections
+            .nicks_map
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|client| {
+                let user = client.user.lock().unwrap();
+                ban.matches(user.user.as_deref().unwrap_or_default(), user.host(), user.ip)
+                    .then(|| (client.clone(), user.certfp.clone()))
+            })
+            .collect::<Vec<_>>();
+        let clients = {
+            let config = self.connections.config.read().unwrap();
+            matched
+                .into_iter()
+                .filter(|(_, certfp)| kind == BanKind::D || !config.is_kline_exempt(certfp.as_deref()))
+                .map(|(client, _)| client)
+                .collect::<Vec<_>>()
+        };
+
+        for client in clients {
+            let _ = client
+                .disconnect
+                .send(format!("{}: {}", kind.name(), reason));
+        }
+
+        Ok(())
+    }
+
+    async fn remove_ban(&mut self, kind: BanKind, mask: &str) -> Result<()> {
+        if !self.check_privilege(kind.privilege()).await? {
+            return Ok(());
+        }
+
+        let nick = self.nick()?;
+        let notice = match self.connections.bans.lock().unwrap().remove(kind, mask) {
+            Ok(true) => format!("{} for [{}] is removed", kind.name(), mask),
+            Ok(false) => format!("No {} for [{}] found", kind.name(), mask),
+            Err(e) => format!("{} for [{}] is removed but could not be saved: {}", kind.name(), mask, e),
+        };
+        self.sender
+            .send(format!(":{} NOTICE {} :{}\r\n", HOST, nick, notice))?;
+
+        Ok(())
+    }
+
+    async fn cap(&mut self, subcommand: &str, args: &[&str]) -> Result<()> {
+        let nick = self.user().nick.clone().unwrap_or("*".into());
+
+        match subcommand {
+            "LS" => {
+                if !self.authenticated {
+                    self.cap_negotiating = true;
+                }
+                let with_values = args
+                    .first()
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .is_some_and(|v| v >= 302);
+                let secure = self.user().secure;
+                let sts = capabilities::sts_policy(&self.connections.config.read().unwrap(), secure);
+                self.sender.send(format!(
+                    ":{} CAP {} LS :{}\r\n",
+                    HOST, nick, capabilities::ls(with_values, sts.as_deref())
+                ))?;
+            }
+            "LIST" => {
+                let enabled = self.user().caps.iter().cloned().collect::<Vec<_>>();
+                self.sender.send(format!(
+                    ":{} CAP {} LIST :{}\r\n",
+                    HOST, nick, enabled.join(" ")
+                ))?;
+            }
+            "REQ" => {
+                if !self.authenticated {
+                    self.cap_negotiating = true;
+                }
+                let all_supported = args
+                    .iter()
+                    .all(|cap| capabilities::is_supported(cap.trim_start_matches('-')));
+                let reply = if all_supported { "ACK" } else { "NAK" };
+
+                if all_supported {
+                    let mut user = self.user();
+                    for cap in args {
+                        match cap.strip_prefix('-') {
+                            Some(cap) => user.caps.remove(cap),
+                            None => user.caps.insert(cap.to_string()),
+                        };
+                    }
+                }
+
+                self.sender.send(format!(
+                    ":{} CAP {} {} :{}\r\n",
+                    HOST, nick, reply, args.join(" ")
+                ))?;
+            }
+            "END" => {
+                self.cap_negotiating = false;
+                self.check_authenticated().await?;
+            }
+            _ => {
+                self.sender.send(format!(
+                    ":{} {} {} {} :Invalid CAP command\r\n",
+                    HOST, errorcodes::ERR_INVALIDCAPCMD, nick, subcommand
+                ))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn authenticate(&mut self, data: &str) -> Result<()> {
+        let (nick, account, certfp, user_name, host, has_sasl) = {
+            let user = self.user();
+            (
+                user.nick.clone().unwrap_or("*".into()),
+                user.account.clone(),
+                user.certfp.clone(),
+                user.user.clone().unwrap_or("*".into()),
+                user.host().to_string(),
+                user.has_cap(capabilities::SASL),
+            )
+        };
+        let reply = |code: &str, text: &str| format!(":{} {} {} :{}\r\n", HOST, code, nick, text);
+
+        if !has_sasl {
+            self.sender.send(reply(errorcodes::ERR_SASLFAIL, "SASL authentication failed"))?;
+            return Ok(());
+        }
+        if account.is_some() {
+            self.sender.send(reply(errorcodes::ERR_SASLALREADY, "You have already authenticated using SASL"))?;
+            return Ok(());
+        }
+        if data == "*" {
+            self.sasl_external = false;
+            self.sender.send(reply(errorcodes::ERR_SASLABORTED, "SASL authentication aborted"))?;
+            return Ok(());
+        }
+
+        if !self.sasl_external {
+            if data.eq_ignore_ascii_case("EXTERNAL") {
+                self.sasl_external = true;
+                self.sender.send("AUTHENTICATE +\r\n".into())?;
+            } else {
+                self.sender.send(format!(
+                    ":{} {} {} {} :are available SASL mechanisms\r\n{}",
+                    HOST, errorcodes::RPL_SASLMECHS, nick, capabilities::SASL_MECHANISMS,
+                    reply(errorcodes::ERR_SASLFAIL, "SASL authentication failed")
+                ))?;
+            }
+            return Ok(());
+        }
+        self.sasl_external = false;
+
+        // The response is an optional authorization identity, `+` if empty.
+        let authzid = match data {
+            "+" => None,
+            data => BASE64.decode(data).ok().and_then(|bytes| String::from_utf8(bytes).ok()),
+        };
+        let found = certfp.and_then(|certfp| {
+            let config = self.connections.config.read().unwrap();
+            config.find_account(&certfp).map(|account| account.name.clone())
+        });
+
+        match found {
+            Some(account) if authzid.as_ref().is_none_or(|authzid| *authzid == account) => {
+                {
+                    let mut user = self.user();
+                    user.account = Some(account.clone());
+                    user.modes.insert('r');
+                }
+                self.sender.send(format!(
+                    ":{} {} {} {}!{}@{} {} :You are now logged in as {}\r\n{}",
+                    HOST, errorcodes::RPL_LOGGEDIN, nick, nick, user_name, host, account, account,
+                    reply(errorcodes::RPL_SASLSUCCESS, "SASL authentication successful")
+                ))?;
+                if self.authenticated {
+                    self.sender.send(format!(":{} MODE {} :+r\r\n", nick, nick))?;
+                }
+            }
+            _ => {
+                self.sender.send(reply(errorcodes::ERR_SASLFAIL, "SASL authentication failed"))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Takes on the address of the user behind a trusted gateway. It has to
+    // come before registration, and anything the gateway got wrong closes
+    // the connection.
+    async fn webirc(&mut self, password: &str, hostname: &str, ip: &str, flags: &[&str]) -> Result<()> {
+        if self.authenticated || self.webirc {
+            let nick = self.user().nick.clone().unwrap_or("*".into());
+            self.sender.send(format!(
+                ":{} {} {} :You may not reregister\r\n",
+                HOST, errorcodes::ERR_ALREADYREGISTRED, nick
+            ))?;
+            return Ok(());
+        }
+
+        let gateways = self.connections.config.read().unwrap().webirc_gateways(self.address.ip());
+        let password = password.to_owned();
+        let trusted = tokio::task::spawn_blocking(move || {
+            gateways.iter().any(|gateway| gateway.check_password(&password))
+        })
+        .await?;
+        if !trusted {
+            return self.disconnect("Invalid WEBIRC gateway").await;
+        }
+        let Ok(ip) = ip.parse::<IpAddr>() else {
+            return self.disconnect("Invalid WEBIRC address").await;
+        };
+
+        if let Some(ban) = self.connections.find_dline(ip) {
+            return self.disconnect(&format!("D-lined: {}", ban.reason)).await;
+        }
+
+        self.webirc = true;
+        self.host_verified = true;
+        // An ident reply came from the gateway, not from the user.
+        self.ident = None;
+        self.address = SocketAddr::new(ip, self.address.port());
+        let mut user = self.user();
+        user.ip = Some(ip);
+        user.host = Some(webirc_host(hostname, ip));
+        // Both the link from the user to the gateway and the one from the
+        // gateway to us have to be secure, and the gateway's own
+        // certificate says nothing about the user.
+        user.secure = user.secure && flags.contains(&"secure");
+        user.certfp = None;
+
+        Ok(())
+    }
+
+    async fn start_batch(&mut self, reference: &str, batch_type: &str, params: &[&str]) -> Result<()> {
+        let enabled = {
+            let user = self.user();
+            user.has_cap(capabilities::BATCH) && user.has_cap(capabilities::MULTILINE)
+        };
+
+        if batch_type != MULTILINE_BATCH_TYPE || !enabled || self.multiline.is_some() {
+            self.sender.send(format!(
+                ":{} FAIL BATCH MULTILINE_INVALID :Unsupported batch\r\n",
+                HOST
+            ))?;
+            return Ok(());
+        }
+
+        let Some(target) = params.first() else {
+            self.sender.send(format!(
+                ":{} FAIL BATCH MULTILINE_INVALID_TARGET :No target given\r\n",
+                HOST
+            ))?;
+            return Ok(());
+        };
+
+        self.multiline = Some(MultilineBuffer::new(reference, target));
+        Ok(())
+    }
+
+    fn in_multiline(&self, tags: &[Tag]) -> bool {
+        let Some(Tag {
+            value: Some(reference),
+            ..
+        }) = find_tag(tags, "batch")
+        else {
+            return false;
+        };
+
+        self.multiline
+            .as_ref()
+            .is_some_and(|b| b.reference == *reference && !b.failed)
+    }
+
+    async fn add_to_multiline<'a>(
+        &mut self,
+        reference: &str,
+        tags: &[Tag<'a>],
+        message: &UserMessage<'a>,
+    ) -> Result<()> {
+        let Some(buffer) = self.multiline.as_mut().filter(|b| b.reference == reference) else {
+            self.sender.send(format!(
+                ":{} FAIL BATCH INVALID_REFTAG {} :No such batch is open\r\n",
+                HOST, reference
+            ))?;
+            return Ok(());
+        };
+
+        if buffer.failed {
+            return Ok(());
+        }
+
+        let (target, text) = match message {
+            UserMessage::MessageToChannel { channel, message } => (*channel, *message),
+            UserMessage::PrivateMessage { receivers, message } if receivers.len() == 1 => {
+                (receivers[0], *message)
+            }
+            _ => ("", ""),
+        };
+        let text = text.strip_prefix(':').unwrap_or(text);
+        let concat = find_tag(tags, MULTILINE_CONCAT_TAG).is_some();
+        buffer.utf8 &= self.input_utf8;
+
+        if let Err(error) = buffer.push(target, text, concat) {
+            buffer.failed = true;
+            self.sender.send(error.fail_message(HOST))?;
+        }
+
+        Ok(())
+    }
+
+    async fn end_batch(&mut self, reference: &str) -> Result<()> {
+        let buffer = match self.multiline.take() {
+            Some(buffer) if buffer.reference == reference => buffer,
+            other => {
+                self.multiline = other;
+                return Ok(());
+            }
+        };
+
+        if buffer.failed || buffer.lines.is_empty() {
+            return Ok(());
+        }
+
+        let source = self.source()?;
+        let batch = buffer.to_batch(&source).build();
+        self.user().last_active = Instant::now();
+        let fallback = buffer.fallback(&source);
+
+        let message_fn = |_: &'_ str, user: &User| {
+            if user.has_cap(capabilities::BATCH) && user.has_cap(capabilities::MULTILINE) {
+                batch.clone()
+            } else {
+                fallback.clone()
+            }
+        };
+
+        if buffer.target.starts_with('#') {
+            let oclone = self.connections.channels.clone();
+            let channels = oclone.lock().await;
+            let nick = self.nick()?;
+
+            if !buffer.utf8 && channels.get(&buffer.target).is_some_and(|c| c.is_utf8_only()) {
+                self.sender.send(cannot_send_utf8(&nick, &buffer.target))?;
+                return Ok(());
+            }
+
+            let nicks = channels.channel_list(&buffer.target).filter(|s| **s != *nick);
+
+            self.connections
+                .send_msg_to_users(message_fn, nicks.map(|s| s.as_str()))
+                .await;
+        } else {
+            self.connections
+                .send_msg_to_users(message_fn, std::iter::once(buffer.target.as_str()))
+                .await;
+        }
+
+        Ok(())
+    }
+}
+
+fn cannot_send_utf8(nick: &str, channel: &str) -> String {
+    format!(
+        ":{} {} {} {} :Cannot send to channel (+U, only UTF-8 is allowed)\r\n",
+        HOST, errorcodes::ERR_CANNOTSENDTOCHAN, nick, channel
+    )
+}
+
+fn who_reply(
+    me: &str,
+    channel: &str,
+    nick: &str,
+    user: &User,
+    membership: Option<&Membership>,
+    whox: Option<&str>,
+    show_ip: bool,
+) -> String {
+    let mut flags = String::from(if user.away.is_some() { "G" } else { "H" });
+    if user.is_oper() {
+        flags.push('*');
+    }
+    if let Some(membership) = membership {
+        flags.push_str(membership.prefix());
+    }
+
+    let user_name = user.user.as_deref().unwrap_or("*");
+    let host = user.host();
+    let full_name = user.full_name.as_deref().unwrap_or("");
+
+    let Some(whox) = whox else {
+        return format!(
+            ":{} {} {} {} {} {} {} {} {} :0 {}\r\n",
+            HOST, errorcodes::RPL_WHOREPLY, me, channel, user_name, host, HOST, nick, flags,
+            full_name
+        );
+    };
+
+    let (fields, token) = whox.split_once(',').unwrap_or((whox, ""));
+    let mut reply = format!(":{} {} {}", HOST, errorcodes::RPL_WHOSPCRPL, me);
+
+    // WHOX fields are always sent in this order, whatever order they were requested in.
+    for field in "tcuihsnfdlaor".chars().filter(|f| fields.contains(*f)) {
+        let value = match field {
+            't' => token.to_string(),
+            'c' => channel.to_string(),
+            'u' => user_name.to_string(),
+            'i' => user
+                .ip
+                .filter(|_| show_ip)
+                .map(|ip| ip.to_string())
+                .unwrap_or("255.255.255.255".into()),
+            'h' => host.to_string(),
+            's' => HOST.to_string(),
+            'n' => nick.to_string(),
+            'f' => flags.clone(),
+            'd' => "0".to_string(),
+            'l' => user.last_active.elapsed().as_secs().to_string(),
+            'a' => user.account.clone().unwrap_or("0".into()),
+            'o' => "n/a".to_string(),
+            'r' => format!(":{}", full_name),
+            _ => continue,
+        };
+        reply.push(' ');
+        reply.push_str(&value);
+    }
+    reply.push_str("\r\n");
+
+    reply
+}
+
+// Gateways that could not resolve the user pass the address instead.
+fn webirc_host(hostname: &str, ip: IpAddr) -> String {
+    let valid = !hostname.is_empty()
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':'));
+    if valid && !hostname.starts_with(':') {
+        hostname.to_string()
+    } else if valid {
+        format!("0{}", hostname)
+    } else {
+        ip_host(ip)
+    }
+}
+
+// The host of clients whose address has no name. An IPv6 address cannot
+// start with `:` in a message.
+fn ip_host(ip: IpAddr) -> String {
+    let host = ip.to_string();
+    if host.starts_with(':') {
+        format!("0{}", host)
+    } else {
+        host
+    }
 }
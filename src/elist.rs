@@ -0,0 +1,74 @@
+use std::time::{Duration, SystemTime};
+
+use crate::mask;
+
+#[cfg(test)]
+#[path = "./elist_test.rs"]
+mod elist_test;
+
+pub struct ListEntry {
+    pub name: String,
+    pub users: usize,
+    pub topic: String,
+    pub created: SystemTime,
+    pub topic_time: Option<SystemTime>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ListFilter {
+    MoreUsers(usize),
+    FewerUsers(usize),
+    CreatedBefore(Duration),
+    CreatedWithin(Duration),
+    TopicBefore(Duration),
+    TopicWithin(Duration),
+    Name(String),
+    NotName(String),
+}
+
+impl ListFilter {
+    pub fn parse(param: &str) -> Option<ListFilter> {
+        let minutes = |s: &str| s.parse::<u64>().ok().map(|m| Duration::from_secs(m * 60));
+
+        let filter = match param.as_bytes() {
+            [b'>', ..] => ListFilter::MoreUsers(param[1..].parse().ok()?),
+            [b'<', ..] => ListFilter::FewerUsers(param[1..].parse().ok()?),
+            [b'C', b'<', ..] => ListFilter::CreatedWithin(minutes(&param[2..])?),
+            [b'C', b'>', ..] => ListFilter::CreatedBefore(minutes(&param[2..])?),
+            [b'T', b'<', ..] => ListFilter::TopicWithin(minutes(&param[2..])?),
+            [b'T', b'>', ..] => ListFilter::TopicBefore(minutes(&param[2..])?),
+            [b'!', ..] => ListFilter::NotName(param[1..].into()),
+            [] => return None,
+            _ => ListFilter::Name(param.into()),
+        };
+
+        Some(filter)
+    }
+
+    fn matches(&self, entry: &ListEntry, now: SystemTime) -> bool {
+        let age = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+
+        match self {
+            ListFilter::MoreUsers(n) => entry.users > *n,
+            ListFilter::FewerUsers(n) => entry.users < *n,
+            ListFilter::CreatedWithin(d) => age(entry.created) < *d,
+            ListFilter::CreatedBefore(d) => age(entry.created) > *d,
+            ListFilter::TopicWithin(d) => entry.topic_time.is_some_and(|t| age(t) < *d),
+            ListFilter::TopicBefore(d) => entry.topic_time.is_some_and(|t| age(t) > *d),
+            ListFilter::Name(name) => mask::matches(name, &entry.name),
+            ListFilter::NotName(name) => !mask::matches(name, &entry.name),
+        }
+    }
+}
+
+// Channel names and positive masks select channels (any of them may match);
+// every other filter must hold.
+pub fn matches_all(filters: &[ListFilter], entry: &ListEntry) -> bool {
+    let now = SystemTime::now();
+    let (names, conditions): (Vec<_>, Vec<_>) = filters
+        .iter()
+        .partition(|f| matches!(f, ListFilter::Name(_)));
+
+    (names.is_empty() || names.iter().any(|f| f.matches(entry, now)))
+        && conditions.iter().all(|f| f.matches(entry, now))
+}
@@ -0,0 +1,62 @@
+use super::*;
+
+fn entry(name: &str, users: usize, age_minutes: u64) -> ListEntry {
+    let created = SystemTime::now() - Duration::from_secs(age_minutes * 60);
+    ListEntry {
+        name: name.into(),
+        users,
+        topic: String::new(),
+        created,
+        topic_time: None,
+    }
+}
+
+#[test]
+fn test_parse_filters() {
+    assert_eq!(Some(ListFilter::MoreUsers(5)), ListFilter::parse(">5"));
+    assert_eq!(Some(ListFilter::FewerUsers(3)), ListFilter::parse("<3"));
+    assert_eq!(
+        Some(ListFilter::CreatedWithin(Duration::from_secs(600))),
+        ListFilter::parse("C<10")
+    );
+    assert_eq!(
+        Some(ListFilter::TopicBefore(Duration::from_secs(60))),
+        ListFilter::parse("T>1")
+    );
+    assert_eq!(
+        Some(ListFilter::NotName("#priv*".into())),
+        ListFilter::parse("!#priv*")
+    );
+    assert_eq!(Some(ListFilter::Name("#rust".into())), ListFilter::parse("#rust"));
+    assert_eq!(None, ListFilter::parse(">many"));
+    assert_eq!(None, ListFilter::parse(""));
+}
+
+#[test]
+fn test_matches_all() {
+    let rust = entry("#rust", 10, 120);
+    let go = entry("#go", 2, 5);
+
+    let filters = [ListFilter::MoreUsers(5)];
+    assert!(matches_all(&filters, &rust));
+    assert!(!matches_all(&filters, &go));
+
+    let filters = [ListFilter::CreatedWithin(Duration::from_secs(600))];
+    assert!(!matches_all(&filters, &rust));
+    assert!(matches_all(&filters, &go));
+
+    let filters = [
+        ListFilter::Name("#RUST".into()),
+        ListFilter::Name("#g?".into()),
+        ListFilter::FewerUsers(5),
+    ];
+    assert!(!matches_all(&filters, &rust));
+    assert!(matches_all(&filters, &go));
+
+    let filters = [ListFilter::NotName("#r*".into())];
+    assert!(!matches_all(&filters, &rust));
+    assert!(matches_all(&filters, &go));
+
+    let filters = [ListFilter::TopicWithin(Duration::from_secs(600))];
+    assert!(!matches_all(&filters, &go));
+}
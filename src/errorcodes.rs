@@ -63,6 +63,7 @@ pub const RPL_TRACEEND: &str = "262";
 pub const RPL_TRYAGAIN: &str = "263";
 pub const RPL_LOCALUSERS: &str = "265";
 pub const RPL_GLOBALUSERS: &str = "266";
+pub const RPL_WHOISCERTFP: &str = "276";
 pub const RPL_NONE: &str = "300";
 pub const RPL_AWAY: &str = "301";
 pub const RPL_USERHOST: &str = "302";
@@ -84,6 +85,7 @@ pub const RPL_LIST: &str = "322";
 pub const RPL_LISTEND: &str = "323";
 pub const RPL_CHANNELMODEIS: &str = "324";
 pub const RPL_UNIQOPIS: &str = "325";
+pub const RPL_WHOISACCOUNT: &str = "330";
 pub const RPL_NOTOPIC: &str = "331";
 pub const RPL_TOPIC: &str = "332";
 pub const RPL_TOPIC_WHO_TIME: &str = "333";
@@ -98,6 +100,7 @@ pub const RPL_ENDOFEXCEPTLIST: &str = "349";
 pub const RPL_VERSION: &str = "351";
 pub const RPL_WHOREPLY: &str = "352";
 pub const RPL_NAMREPLY: &str = "353";
+pub const RPL_WHOSPCRPL: &str = "354";
 pub const RPL_KILLDONE: &str = "361";
 pub const RPL_CLOSING: &str = "362";
 pub const RPL_CLOSEEND: &str = "363";
@@ -132,12 +135,14 @@ pub const ERR_WASNOSUCHNICK: &str = "406";
 pub const ERR_TOOMANYTARGETS: &str = "407";
 pub const ERR_NOSUCHSERVICE: &str = "408";
 pub const ERR_NOORIGIN: &str = "409";
+pub const ERR_INVALIDCAPCMD: &str = "410";
 pub const ERR_NORECIPIENT: &str = "411";
 pub const ERR_NOTEXTTOSEND: &str = "412";
 pub const ERR_NOTOPLEVEL: &str = "413";
 pub const ERR_WILDTOPLEVEL: &str = "414";
 pub const ERR_BADMASK: &str = "415";
 pub const ERR_TOOMANYMATCHES: &str = "416";
+pub const ERR_INPUTTOOLONG: &str = "417";
 pub const ERR_UNKNOWNCOMMAND: &str = "421";
 pub const ERR_NOMOTD: &str = "422";
 pub const ERR_NOADMININFO: &str = "423";
@@ -176,10 +181,18 @@ pub const ERR_CHANOPRIVSNEEDED: &str = "482";
 pub const ERR_CANTKILLSERVER: &str = "483";
 pub const ERR_RESTRICTED: &str = "484";
 pub const ERR_UNIQOPRIVSNEEDED: &str = "485";
+pub const ERR_SECUREONLYCHAN: &str = "489";
 pub const ERR_NOOPERHOST: &str = "491";
 pub const ERR_NOSERVICEHOST: &str = "492";
 pub const ERR_STATSKLINE: &str = "499";
 pub const ERR_UMODEUNKNOWNFLAG: &str = "501";
 pub const ERR_USERSDONTMATCH: &str = "502";
+pub const RPL_WHOISSECURE: &str = "671";
 pub const RPL_ETRACEFULL: &str = "708";
 pub const RPL_ETRACEEND: &str = "759";
+pub const RPL_LOGGEDIN: &str = "900";
+pub const RPL_SASLSUCCESS: &str = "903";
+pub const ERR_SASLFAIL: &str = "904";
+pub const ERR_SASLABORTED: &str = "906";
+pub const ERR_SASLALREADY: &str = "907";
+pub const RPL_SASLMECHS: &str = "908";
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+use crate::config::FloodConfig;
+use crate::messages::UserMessage;
+
+#[cfg(test)]
+#[path = "./flood_test.rs"]
+mod flood_test;
+
+#[derive(Debug, PartialEq)]
+pub enum FloodResult {
+    Allow,
+    // The command has to wait this long before it is handled.
+    Delay(Duration),
+    ExcessFlood,
+}
+
+/// Token bucket for the commands of one connection. Every command takes
+/// `cost` tokens; once the bucket is empty commands are delayed until it
+/// refills, and a client that keeps sending while throttled is dropped.
+#[derive(Debug)]
+pub struct FloodControl {
+    config: FloodConfig,
+    tokens: f64,
+    last: Instant,
+    throttled: u32,
+}
+
+impl FloodControl {
+    pub fn new(config: FloodConfig) -> Self {
+        FloodControl {
+            tokens: config.burst as f64,
+            config,
+            last: Instant::now(),
+            throttled: 0,
+        }
+    }
+
+    pub fn consume(&mut self, cost: u32, now: Instant) -> FloodResult {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.rate).min(self.config.burst as f64);
+        self.last = now;
+
+        self.tokens -= cost as f64;
+        if self.tokens >= 0.0 {
+            self.throttled = 0;
+            return FloodResult::Allow;
+        }
+
+        self.throttled += 1;
+        if self.throttled > self.config.excess || self.config.rate <= 0.0 {
+            return FloodResult::ExcessFlood;
+        }
+
+        FloodResult::Delay(Duration::from_secs_f64(-self.tokens / self.config.rate))
+    }
+}
+
+// Commands that fan out to several targets cost one token per target.
+pub fn cost(message: &UserMessage) -> u32 {
+    let targets = match message {
+        UserMessage::PrivateMessage { receivers, .. } => receivers.len(),
+        UserMessage::Join { channels, .. } => channels.len(),
+        UserMessage::Kick { channels, users, .. } => channels.len().max(users.len()),
+        UserMessage::Names { channels } => channels.len(),
+        UserMessage::Whois { nicks } | UserMessage::Whowas { nicks, .. } => nicks.len(),
+        UserMessage::List { .. } | UserMessage::Who { .. } => 2,
+        _ => 1,
+    };
+
+    targets.max(1) as u32
+}
@@ -0,0 +1,66 @@
+use super::*;
+
+use crate::messages::parse_message;
+
+fn flood_control(burst: u32, rate: f64, excess: u32) -> FloodControl {
+    FloodControl::new(FloodConfig {
+        burst,
+        rate,
+        excess,
+    })
+}
+
+#[test]
+fn test_burst_then_delay() {
+    let mut flood = flood_control(3, 2.0, 5);
+    let now = flood.last;
+
+    for _ in 0..3 {
+        assert_eq!(FloodResult::Allow, flood.consume(1, now));
+    }
+    assert_eq!(
+        FloodResult::Delay(Duration::from_millis(500)),
+        flood.consume(1, now)
+    );
+
+    // After waiting out the delay the next command is throttled again,
+    // until the client slows down to the refill rate.
+    let now = now + Duration::from_millis(500);
+    assert_eq!(
+        FloodResult::Delay(Duration::from_millis(500)),
+        flood.consume(1, now)
+    );
+    let now = now + Duration::from_millis(1500);
+    assert_eq!(FloodResult::Allow, flood.consume(1, now));
+}
+
+#[test]
+fn test_refill_is_capped_at_burst() {
+    let mut flood = flood_control(2, 1.0, 0);
+    let now = flood.last + Duration::from_secs(60);
+
+    assert_eq!(FloodResult::Allow, flood.consume(1, now));
+    assert_eq!(FloodResult::Allow, flood.consume(1, now));
+    assert_eq!(FloodResult::ExcessFlood, flood.consume(1, now));
+}
+
+#[test]
+fn test_excess_flood() {
+    let mut flood = flood_control(1, 1.0, 2);
+    let now = flood.last;
+
+    assert_eq!(FloodResult::Allow, flood.consume(1, now));
+    assert!(matches!(flood.consume(1, now), FloodResult::Delay(_)));
+    assert!(matches!(flood.consume(1, now), FloodResult::Delay(_)));
+    assert_eq!(FloodResult::ExcessFlood, flood.consume(1, now));
+}
+
+#[test]
+fn test_cost() {
+    assert_eq!(1, cost(&parse_message("PING 123")));
+    assert_eq!(1, cost(&parse_message("PRIVMSG bob :hi")));
+    assert_eq!(3, cost(&parse_message("PRIVMSG bob,joe,#rust :hi")));
+    assert_eq!(2, cost(&parse_message("JOIN #rust,#go")));
+    assert_eq!(2, cost(&parse_message("KICK #rust bob,joe")));
+    assert_eq!(2, cost(&parse_message("LIST")));
+}
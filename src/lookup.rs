@@ -0,0 +1,153 @@
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpStream};
+
+#[cfg(test)]
+#[path = "./lookup_test.rs"]
+mod lookup_test;
+
+pub const IDENT_PORT: u16 = 113;
+// Longest host we take from DNS, as in most servers.
+const HOST_MAX_LENGTH: usize = 63;
+const USER_MAX_LENGTH: usize = 10;
+// RFC 1413 replies are at most 1000 characters.
+const IDENT_MAX_LENGTH: u64 = 1000;
+
+/// Where the server looks up the clients that connect. The system one is
+/// used unless something else, like a stand-in in tests, is put in
+/// `Connections::resolver`.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    // The name the PTR record of `ip` gives, if there is one.
+    async fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>>;
+    async fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+    // The reply line of the ident server on `remote`, asked about the
+    // connection between `remote` and `local`.
+    async fn ident(&self, remote: SocketAddr, local: SocketAddr) -> io::Result<String>;
+}
+
+/// Resolves with the system's own configuration, `/etc/hosts` included.
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>> {
+        tokio::task::spawn_blocking(move || name_info(ip)).await?
+    }
+
+    async fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Ok(lookup_host((host, 0)).await?.map(|addr| addr.ip()).collect())
+    }
+
+    async fn ident(&self, remote: SocketAddr, local: SocketAddr) -> io::Result<String> {
+        let mut stream = TcpStream::connect((remote.ip(), IDENT_PORT)).await?;
+        stream
+            .write_all(format!("{}, {}\r\n", remote.port(), local.port()).as_bytes())
+            .await?;
+
+        let mut reply = String::new();
+        BufReader::new(stream.take(IDENT_MAX_LENGTH))
+            .read_line(&mut reply)
+            .await?;
+        Ok(reply)
+    }
+}
+
+// getnameinfo(3) with NI_NAMEREQD, so that an address without a name is
+// not handed back as text.
+fn name_info(ip: IpAddr) -> io::Result<Option<String>> {
+    // SAFETY: the socket address is written within a zeroed
+    // sockaddr_storage, which is large enough for either family, and
+    // getnameinfo writes a NUL-terminated name within `host`.
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let length = match ip {
+            IpAddr::V4(ip) => {
+                let addr = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                addr.sin_family = libc::AF_INET as libc::sa_family_t;
+                addr.sin_addr.s_addr = u32::from(ip).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            IpAddr::V6(ip) => {
+                let addr = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                addr.sin6_addr.s6_addr = ip.octets();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+        let result = libc::getnameinfo(
+            &storage as *const _ as *const libc::sockaddr,
+            length as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        );
+
+        match result {
+            0 => Ok(Some(CStr::from_ptr(host.as_ptr()).to_string_lossy().into_owned())),
+            libc::EAI_NONAME => Ok(None),
+            libc::EAI_SYSTEM => Err(io::Error::last_os_error()),
+            _ => Err(io::Error::other(
+                CStr::from_ptr(libc::gai_strerror(result)).to_string_lossy().into_owned(),
+            )),
+        }
+    }
+}
+
+/// Forward-confirmed reverse DNS: the name of `ip` counts only when it
+/// resolves back to `ip`.
+pub async fn verified_host(resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+    let host = resolver.reverse(ip).await.ok()??;
+    let host = host.strip_suffix('.').unwrap_or(&host).to_ascii_lowercase();
+    if !valid_host(&host) {
+        return None;
+    }
+
+    let addresses = resolver.forward(&host).await.ok()?;
+    addresses.contains(&ip).then_some(host)
+}
+
+// A name that cannot be mistaken for an address or break a hostmask.
+fn valid_host(host: &str) -> bool {
+    host.len() <= HOST_MAX_LENGTH
+        && host.parse::<IpAddr>().is_err()
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// The user name in an RFC 1413 reply, like
+/// `6193, 23 : USERID : UNIX : stjohns`, for the connection between the
+/// two ports. Anything that would not fit in a hostmask is dropped.
+pub fn parse_ident_reply(reply: &str, remote_port: u16, local_port: u16) -> Option<String> {
+    let [ports, kind, _os, user] = reply.trim_end().splitn(4, ':').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+
+    let (remote, local) = ports.split_once(',')?;
+    if remote.trim().parse() != Ok(remote_port) || local.trim().parse() != Ok(local_port) {
+        return None;
+    }
+    if kind.trim() != "USERID" {
+        return None;
+    }
+
+    let user = user
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(USER_MAX_LENGTH)
+        .collect::<String>();
+    (!user.is_empty()).then_some(user)
+}
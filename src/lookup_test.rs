@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use super::*;
+
+// Answers from tables instead of the network.
+#[derive(Default)]
+struct StandIn {
+    names: HashMap<IpAddr, String>,
+    addresses: HashMap<String, Vec<IpAddr>>,
+}
+
+#[async_trait]
+impl Resolver for StandIn {
+    async fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>> {
+        Ok(self.names.get(&ip).cloned())
+    }
+
+    async fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Ok(self.addresses.get(host).cloned().unwrap_or_default())
+    }
+
+    async fn ident(&self, _remote: SocketAddr, _local: SocketAddr) -> io::Result<String> {
+        Err(io::ErrorKind::ConnectionRefused.into())
+    }
+}
+
+#[tokio::test]
+async fn test_verified_host() {
+    let good: IpAddr = "192.0.2.1".parse().unwrap();
+    let spoofed: IpAddr = "192.0.2.2".parse().unwrap();
+    let bad: IpAddr = "192.0.2.3".parse().unwrap();
+    let unknown: IpAddr = "192.0.2.4".parse().unwrap();
+
+    let mut resolver = StandIn::default();
+    resolver.names.insert(good, "Client.Example.com.".into());
+    resolver.addresses.insert("client.example.com".into(), vec![good]);
+    resolver.names.insert(spoofed, "bank.example.com".into());
+    resolver.addresses.insert("bank.example.com".into(), vec!["198.51.100.1".parse().unwrap()]);
+    resolver.names.insert(bad, "192.0.2.3".into());
+    resolver.addresses.insert("192.0.2.3".into(), vec![bad]);
+
+    assert_eq!(Some("client.example.com".into()), verified_host(&resolver, good).await);
+    assert_eq!(None, verified_host(&resolver, spoofed).await);
+    assert_eq!(None, verified_host(&resolver, bad).await);
+    assert_eq!(None, verified_host(&resolver, unknown).await);
+}
+
+#[test]
+fn test_valid_host() {
+    assert!(valid_host("client.example.com"));
+    assert!(valid_host("a-1.example.com"));
+    assert!(!valid_host("10.0.0.1"));
+    assert!(!valid_host("evil!nick@example.com"));
+    assert!(!valid_host("-bad.example.com"));
+    assert!(!valid_host("double..dot"));
+    assert!(!valid_host(&format!("{}.example.com", "a".repeat(60))));
+}
+
+#[test]
+fn test_parse_ident_reply() {
+    assert_eq!(
+        Some("stjohns".into()),
+        parse_ident_reply("6193, 23 : USERID : UNIX : stjohns\r\n", 6193, 23)
+    );
+    assert_eq!(
+        Some("oddname".into()),
+        parse_ident_reply("6193,23:USERID:UNIX,UTF-8:odd:name", 6193, 23)
+    );
+    assert_eq!(
+        Some("averyveryl".into()),
+        parse_ident_reply("6193, 23 : USERID : UNIX : averyverylongname", 6193, 23)
+    );
+    assert_eq!(None, parse_ident_reply("6193, 23 : ERROR : NO-USER", 6193, 23));
+    assert_eq!(None, parse_ident_reply("6193, 24 : USERID : UNIX : stjohns", 6193, 23));
+    assert_eq!(None, parse_ident_reply("6193, 23 : USERID : UNIX : !@ ", 6193, 23));
+    assert_eq!(None, parse_ident_reply("garbage", 6193, 23));
+}
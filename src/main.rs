@@ -1,19 +1,39 @@
+mod bans;
+mod batch;
+mod capabilities;
 mod channels;
+mod charset;
+mod codec;
+mod config;
 mod connections;
+mod elist;
 mod errorcodes;
+mod flood;
+mod lookup;
+mod mask;
 mod messages;
+mod proxy;
+mod sendq;
 mod server;
+mod tls;
+mod transport;
 mod user;
+mod websocket;
+mod whowas;
 
 use anyhow::Result;
+use config::Config;
 use server::Server;
-use tokio::{self, net::TcpListener};
+use std::path::Path;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let listener = TcpListener::bind(format!("0.0.0.0:6667")).await?;
-    let mut server = Server::new(listener);
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(Path::new(&path))?,
+        None => Config::default(),
+    };
 
+    let mut server = Server::bind(config).await?;
     server.start_server().await?;
     Ok(())
 }
@@ -0,0 +1,48 @@
+#[cfg(test)]
+#[path = "./mask_test.rs"]
+mod mask_test;
+
+// rfc1459 casemapping: besides ASCII letters, `[]\~` are the uppercase
+// forms of `{}|^`.
+pub fn irc_lowercase(c: char) -> char {
+    match c {
+        '[' => '{',
+        ']' => '}',
+        '\\' => '|',
+        '~' => '^',
+        _ => c.to_ascii_lowercase(),
+    }
+}
+
+// Glob match where `*` matches any run of characters and `?` matches exactly
+// one, compared under the rfc1459 casemapping.
+pub fn matches(mask: &str, text: &str) -> bool {
+    let mask = mask.chars().map(irc_lowercase).collect::<Vec<_>>();
+    let text = text.chars().map(irc_lowercase).collect::<Vec<_>>();
+
+    let (mut m, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match mask.get(m) {
+            Some('*') => {
+                backtrack = Some((m, t));
+                m += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                m += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    m = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    mask[m..].iter().all(|c| *c == '*')
+}
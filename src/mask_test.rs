@@ -0,0 +1,23 @@
+use super::*;
+
+#[test]
+fn test_irc_lowercase() {
+    let folded = "NiCK[]\\~".chars().map(irc_lowercase).collect::<String>();
+    assert_eq!("nick{}|^", folded);
+}
+
+#[test]
+fn test_matches() {
+    assert!(matches("*", ""));
+    assert!(matches("*", "anything"));
+    assert!(matches("bob", "BOB"));
+    assert!(matches("b?b", "bob"));
+    assert!(!matches("b?b", "bb"));
+    assert!(matches("*!*@*.example.com", "bob!user@host.example.com"));
+    assert!(!matches("*!*@*.example.com", "bob!user@example.com"));
+    assert!(matches("*a*b*c", "xxaxxbxxc"));
+    assert!(!matches("*a*b*c", "xxaxxbxxcx"));
+    assert!(matches("nick[*", "NICK{away"));
+    assert!(matches("**?", "x"));
+    assert!(!matches("", "x"));
+}
@@ -1,7 +1,15 @@
+use std::time::Duration;
+
+use crate::bans::BanKind;
+
 #[cfg(test)]
 #[path = "./messages_test.rs"]
 mod messages_test;
 
+pub const MAX_LINE_LENGTH: usize = 512;
+// Budget for the `@tags ` prefix of client messages, on top of MAX_LINE_LENGTH.
+pub const MAX_TAGS_LENGTH: usize = 4096;
+
 #[derive(Debug, PartialEq)]
 pub enum UserMessage<'a> {
     Nick {
@@ -36,48 +44,302 @@ pub enum UserMessage<'a> {
         server: &'a str,
     },
     Mode {
-        channel: &'a str,
+        target: &'a str,
         mode: Option<&'a str>,
     },
+    Invite {
+        nick: &'a str,
+        channel: &'a str,
+    },
+    Kick {
+        channels: Vec<&'a str>,
+        users: Vec<&'a str>,
+        reason: Option<&'a str>,
+    },
+    Names {
+        channels: Vec<&'a str>,
+    },
+    List {
+        params: Vec<&'a str>,
+    },
+    Who {
+        mask: Option<&'a str>,
+        options: Option<&'a str>,
+    },
+    Whois {
+        nicks: Vec<&'a str>,
+    },
+    Whowas {
+        nicks: Vec<&'a str>,
+        count: Option<usize>,
+    },
+    Away {
+        message: Option<&'a str>,
+    },
+    Oper {
+        name: &'a str,
+        password: &'a str,
+    },
+    Kill {
+        nick: &'a str,
+        reason: &'a str,
+    },
+    Wallops {
+        message: &'a str,
+    },
+    Rehash,
+    Ban {
+        kind: BanKind,
+        duration: Option<Duration>,
+        mask: &'a str,
+        reason: Option<&'a str>,
+    },
+    Unban {
+        kind: BanKind,
+        mask: &'a str,
+    },
+    Cap {
+        subcommand: &'a str,
+        args: Vec<&'a str>,
+    },
+    BatchStart {
+        reference: &'a str,
+        batch_type: &'a str,
+        params: Vec<&'a str>,
+    },
+    BatchEnd {
+        reference: &'a str,
+    },
+    Authenticate {
+        data: &'a str,
+    },
+    WebIrc {
+        password: &'a str,
+        gateway: &'a str,
+        hostname: &'a str,
+        ip: &'a str,
+        flags: Vec<&'a str>,
+    },
     InvalidMessage,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct Tag<'a> {
+    pub key: &'a str,
+    pub value: Option<&'a str>,
+}
+
+pub fn find_tag<'a, 'b>(tags: &'b [Tag<'a>], key: &str) -> Option<&'b Tag<'a>> {
+    tags.iter().find(|tag| tag.key == key)
+}
+
+// Builds as many `<prefix> :item item ...` lines as needed to keep every one
+// of them, CRLF included, within MAX_LINE_LENGTH. A prefix that leaves no
+// room, like one with a very long channel name, gets one item per line.
+pub fn chunk_reply<'a>(prefix: &str, items: impl Iterator<Item = &'a str>) -> String {
+    let budget = MAX_LINE_LENGTH.saturating_sub(prefix.len() + " :".len() + "\r\n".len());
+    let mut result = String::new();
+    let mut line = String::new();
+
+    for item in items {
+        if !line.is_empty() && line.len() + 1 + item.len() > budget {
+            result.push_str(&format!("{} :{}\r\n", prefix, line));
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(item);
+    }
+
+    if !line.is_empty() {
+        result.push_str(&format!("{} :{}\r\n", prefix, line));
+    }
+
+    result
+}
+
+pub fn parse_mode_changes(modes: &str) -> Vec<(bool, char)> {
+    let mut adding = true;
+    let mut changes = vec![];
+
+    for c in modes.chars() {
+        match c {
+            '+' => adding = true,
+            '-' => adding = false,
+            c => changes.push((adding, c)),
+        }
+    }
+
+    changes
+}
+
+pub fn format_mode_changes(changes: &[(bool, char)]) -> String {
+    let mut result = String::new();
+    let mut current = None;
+
+    for (adding, c) in changes {
+        if current != Some(*adding) {
+            result.push(if *adding { '+' } else { '-' });
+            current = Some(*adding);
+        }
+        result.push(*c);
+    }
+
+    result
+}
+
 fn split(msg: &str) -> Vec<&str> {
+    if msg.is_empty() {
+        return vec![];
+    }
+
     let parts = msg.split(" ");
     parts.collect::<Vec<&str>>()
 }
 
-pub fn parse_message(msg: &str) -> UserMessage {
-    let space_index = msg.find(' ').unwrap_or_default();
-    let head = &msg[0..space_index];
-    let body = &msg[space_index + 1..];
+fn split_tags(msg: &str) -> (Vec<Tag<'_>>, &str) {
+    let Some(tagged) = msg.strip_prefix('@') else {
+        return (vec![], msg);
+    };
+    let (tags_str, rest) = tagged.split_once(' ').unwrap_or((tagged, ""));
+
+    let tags = tags_str
+        .split(';')
+        .filter(|t| !t.is_empty())
+        .map(|t| match t.split_once('=') {
+            Some((key, value)) => Tag {
+                key,
+                value: Some(value),
+            },
+            None => Tag {
+                key: t,
+                value: None,
+            },
+        })
+        .collect();
+
+    (tags, rest.trim_start())
+}
+
+pub fn parse_tagged_message(msg: &str) -> (Vec<Tag<'_>>, UserMessage<'_>) {
+    let (tags, msg) = split_tags(msg);
+    (tags, parse_message(msg))
+}
+
+pub fn parse_message(msg: &str) -> UserMessage<'_> {
+    let msg = msg.trim_end_matches(['\r', '\n']);
+    let (head, body) = msg.split_once(' ').unwrap_or((msg, ""));
 
     match head {
         "NICK" => parse_nick(split(body)),
-        "USER" => parse_user(split(body)),
+        "USER" => parse_user(body),
         "PASS" => {
             let parts = split(body);
-            if let Some(password) = parts.get(0) {
-                return UserMessage::Password { password };
+            if let Some(password) = parts.first() {
+                UserMessage::Password { password }
             } else {
                 UserMessage::InvalidMessage
             }
         }
         "PRIVMSG" => parse_priv_msg(body),
         "QUIT" => {
-            let parts = split(body);
-            let quit_msg = parts.get(0).map(|str| *str);
-            UserMessage::Quit { quit_msg }
+            let quit_msg = body.strip_prefix(':').unwrap_or(body);
+            UserMessage::Quit {
+                quit_msg: Some(quit_msg).filter(|m| !m.is_empty()),
+            }
         }
         "JOIN" => parse_join_msg(split(body)),
         "PING" => {
             let parts = split(body);
-            let server = parts.get(0).map(|str| *str).unwrap_or("");
-            UserMessage::Ping {
-                server: server.trim(),
+            match parts.first() {
+                Some(server) => UserMessage::Ping {
+                    server: server.trim(),
+                },
+                None => UserMessage::InvalidMessage,
             }
         }
         "MODE" => parse_mode_msg(split(body)),
+        "INVITE" => match &split(body)[..] {
+            [nick, channel, ..] => UserMessage::Invite { nick, channel },
+            _ => UserMessage::InvalidMessage,
+        },
+        "KICK" => parse_kick_msg(body),
+        "NAMES" => {
+            let parts = split(body);
+            UserMessage::Names {
+                channels: parts
+                    .first()
+                    .map(|p| p.split(',').filter(|p| !p.is_empty()).collect())
+                    .unwrap_or_default(),
+            }
+        }
+        "LIST" => {
+            let parts = split(body);
+            UserMessage::List {
+                params: parts
+                    .first()
+                    .map(|p| p.split(',').filter(|p| !p.is_empty()).collect())
+                    .unwrap_or_default(),
+            }
+        }
+        "WHO" => {
+            let parts = split(body);
+            UserMessage::Who {
+                mask: parts.first().copied(),
+                options: parts.get(1).copied(),
+            }
+        }
+        "WHOIS" => match &split(body)[..] {
+            [] => UserMessage::Whois { nicks: vec![] },
+            [nicks] | [_, nicks, ..] => UserMessage::Whois {
+                nicks: nicks.split(',').filter(|n| !n.is_empty()).collect(),
+            },
+        },
+        "WHOWAS" => match &split(body)[..] {
+            [] => UserMessage::Whowas {
+                nicks: vec![],
+                count: None,
+            },
+            [nicks, rest @ ..] => UserMessage::Whowas {
+                nicks: nicks.split(',').filter(|n| !n.is_empty()).collect(),
+                count: rest.first().and_then(|c| c.parse::<usize>().ok()),
+            },
+        },
+        "AWAY" => {
+            let message = body.strip_prefix(':').unwrap_or(body);
+            UserMessage::Away {
+                message: Some(message).filter(|m| !m.is_empty()),
+            }
+        }
+        "OPER" => match &split(body)[..] {
+            [name, password, ..] => UserMessage::Oper { name, password },
+            _ => UserMessage::InvalidMessage,
+        },
+        "KILL" => match body.split_once(' ') {
+            Some((nick, reason)) if !nick.is_empty() => UserMessage::Kill {
+                nick,
+                reason: reason.strip_prefix(':').unwrap_or(reason),
+            },
+            _ => UserMessage::InvalidMessage,
+        },
+        "WALLOPS" if !body.is_empty() => UserMessage::Wallops {
+            message: body.strip_prefix(':').unwrap_or(body),
+        },
+        "REHASH" => UserMessage::Rehash,
+        "KLINE" => parse_ban_msg(BanKind::K, body),
+        "DLINE" => parse_ban_msg(BanKind::D, body),
+        "GLINE" => parse_ban_msg(BanKind::G, body),
+        "UNKLINE" => parse_unban_msg(BanKind::K, split(body)),
+        "UNDLINE" => parse_unban_msg(BanKind::D, split(body)),
+        "UNGLINE" => parse_unban_msg(BanKind::G, split(body)),
+        "CAP" => parse_cap_msg(body),
+        "AUTHENTICATE" => match &split(body)[..] {
+            [data, ..] => UserMessage::Authenticate { data },
+            _ => UserMessage::InvalidMessage,
+        },
+        "BATCH" => parse_batch_msg(split(body)),
+        "WEBIRC" => parse_webirc_msg(body),
 
         _ => UserMessage::InvalidMessage,
     }
@@ -86,14 +348,14 @@ pub fn parse_message(msg: &str) -> UserMessage {
 fn parse_nick<'a>(input: Vec<&'a str>) -> UserMessage<'a> {
     match &input[..] {
         [nickname] => UserMessage::Nick {
-            nickname: &nickname.trim(),
+            nickname: nickname.trim(),
             hop_count: 0,
         },
         [nickname, hop_str, ..] => {
             let hop = hop_str.trim().parse::<usize>();
             if let Ok(hop_count) = hop {
                 UserMessage::Nick {
-                    nickname: &nickname.trim(),
+                    nickname: nickname.trim(),
                     hop_count,
                 }
             } else {
@@ -104,13 +366,16 @@ fn parse_nick<'a>(input: Vec<&'a str>) -> UserMessage<'a> {
     }
 }
 
-fn parse_user<'a>(input: Vec<&'a str>) -> UserMessage<'a> {
-    if let [user_name, server_name, host_name, real_name, ..] = &input[..] {
+fn parse_user(input: &str) -> UserMessage<'_> {
+    let parts = input.splitn(4, ' ').collect::<Vec<&str>>();
+
+    if let [user_name, server_name, host_name, real_name] = &parts[..] {
+        let real_name = real_name.trim();
         return UserMessage::User {
-            user_name: &user_name.trim(),
-            host_name: &host_name.trim(),
-            server_name: &server_name.trim(),
-            real_name: &real_name.trim(),
+            user_name: user_name.trim(),
+            host_name: host_name.trim(),
+            server_name: server_name.trim(),
+            real_name: real_name.strip_prefix(':').unwrap_or(real_name),
         };
     }
 
@@ -122,7 +387,7 @@ fn parse_priv_msg<'a>(input: &'a str) -> UserMessage<'a> {
     let head = &input[0..space_index];
     let body = &input[space_index + 1..];
 
-    if head == "" {
+    if head.is_empty() {
         return UserMessage::InvalidMessage;
     }
 
@@ -136,7 +401,7 @@ fn parse_priv_msg<'a>(input: &'a str) -> UserMessage<'a> {
     let parts = head.split(",");
     let receivers = parts.collect::<Vec<&str>>();
     UserMessage::PrivateMessage {
-        receivers: receivers,
+        receivers,
         message: body,
     }
 }
@@ -173,14 +438,117 @@ fn parse_join_msg<'a>(input: Vec<&'a str>) -> UserMessage<'a> {
 
 fn parse_mode_msg<'a>(input: Vec<&'a str>) -> UserMessage<'a> {
     match &input[..] {
-        [channel] => UserMessage::Mode {
-            channel: channel.trim(),
+        [target] => UserMessage::Mode {
+            target: target.trim(),
             mode: None,
         },
-        [channel, mode, ..] => UserMessage::Mode {
-            channel: channel.trim(),
+        [target, mode, ..] => UserMessage::Mode {
+            target: target.trim(),
             mode: Some(*mode),
         },
         _ => UserMessage::InvalidMessage,
     }
 }
+
+fn parse_kick_msg(input: &str) -> UserMessage<'_> {
+    let parts = input.splitn(3, ' ').collect::<Vec<&str>>();
+
+    match &parts[..] {
+        [channels, users, rest @ ..] => {
+            let reason = rest.first().map(|r| r.strip_prefix(':').unwrap_or(r));
+            UserMessage::Kick {
+                channels: channels.split(',').collect(),
+                users: users.split(',').filter(|u| !u.is_empty()).collect(),
+                reason: reason.filter(|r| !r.is_empty()),
+            }
+        }
+        _ => UserMessage::InvalidMessage,
+    }
+}
+
+// `<KLINE|DLINE|GLINE> [minutes] <mask> [:reason]`, where a duration of 0
+// or no duration at all makes the ban permanent.
+fn parse_ban_msg(kind: BanKind, input: &str) -> UserMessage<'_> {
+    let (params, reason) = match input.split_once(" :") {
+        Some((params, reason)) => (params, Some(reason)),
+        None => (input, None),
+    };
+
+    let (duration, mask) = match &split(params)[..] {
+        [minutes, mask, ..] => match minutes.parse::<u64>() {
+            Ok(minutes) => (Some(minutes), *mask),
+            Err(_) => return UserMessage::InvalidMessage,
+        },
+        [mask] => (None, *mask),
+        _ => return UserMessage::InvalidMessage,
+    };
+
+    UserMessage::Ban {
+        kind,
+        duration: duration
+            .filter(|minutes| *minutes > 0)
+            .map(|minutes| Duration::from_secs(minutes * 60)),
+        mask,
+        reason: reason.filter(|r| !r.is_empty()),
+    }
+}
+
+fn parse_unban_msg(kind: BanKind, input: Vec<&str>) -> UserMessage<'_> {
+    match input[..] {
+        [mask, ..] => UserMessage::Unban { kind, mask },
+        _ => UserMessage::InvalidMessage,
+    }
+}
+
+fn parse_cap_msg(input: &str) -> UserMessage<'_> {
+    let (subcommand, rest) = input.split_once(' ').unwrap_or((input, ""));
+
+    if subcommand.is_empty() {
+        return UserMessage::InvalidMessage;
+    }
+
+    let args = rest
+        .strip_prefix(':')
+        .unwrap_or(rest)
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+
+    UserMessage::Cap { subcommand, args }
+}
+
+fn parse_batch_msg<'a>(input: Vec<&'a str>) -> UserMessage<'a> {
+    match &input[..] {
+        [reference] if reference.starts_with('-') => UserMessage::BatchEnd {
+            reference: &reference[1..],
+        },
+        [reference, batch_type, params @ ..] if reference.starts_with('+') => {
+            UserMessage::BatchStart {
+                reference: &reference[1..],
+                batch_type,
+                params: params.to_vec(),
+            }
+        }
+        _ => UserMessage::InvalidMessage,
+    }
+}
+
+// `WEBIRC password gateway hostname ip [:flags]`, where the flags are
+// separated by spaces.
+fn parse_webirc_msg(body: &str) -> UserMessage<'_> {
+    match body.splitn(5, ' ').collect::<Vec<_>>()[..] {
+        [password, gateway, hostname, ip, ref flags @ ..] if !ip.is_empty() => UserMessage::WebIrc {
+            password,
+            gateway,
+            hostname,
+            ip,
+            flags: flags
+                .first()
+                .map(|flags| flags.strip_prefix(':').unwrap_or(flags))
+                .unwrap_or_default()
+                .split(' ')
+                .filter(|f| !f.is_empty())
+                .collect(),
+        },
+        _ => UserMessage::InvalidMessage,
+    }
+}
@@ -117,17 +117,31 @@ fn test_parse_ping() {
     assert_messages(&msgs, &expected);
 }
 
+#[test]
+fn test_parse_without_params() {
+    let msgs = ["QUIT", "QUIT\r\n", "PING ", "PING\r\n"];
+
+    let expected = [
+        UserMessage::Quit { quit_msg: None },
+        UserMessage::Quit { quit_msg: None },
+        UserMessage::InvalidMessage,
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
 #[test]
 fn test_parse_mode() {
     let msgs = ["MODE #channel1 aaa", "MODE #channel1", "MODE"];
 
     let expected = [
         UserMessage::Mode {
-            channel: "#channel1",
+            target: "#channel1",
             mode: Some("aaa"),
         },
         UserMessage::Mode {
-            channel: "#channel1",
+            target: "#channel1",
             mode: None,
         },
         UserMessage::InvalidMessage,
@@ -150,3 +164,409 @@ fn test_pass_msg() {
 
     assert_messages(&msgs, &expected);
 }
+
+#[test]
+fn test_parse_tags() {
+    let (tags, message) =
+        parse_tagged_message("@batch=abc;draft/multiline-concat PRIVMSG #room1 :hello\r\n");
+
+    assert_eq!(
+        vec![
+            Tag {
+                key: "batch",
+                value: Some("abc"),
+            },
+            Tag {
+                key: "draft/multiline-concat",
+                value: None,
+            },
+        ],
+        tags
+    );
+    assert_eq!(
+        UserMessage::MessageToChannel {
+            channel: "#room1",
+            message: ":hello",
+        },
+        message
+    );
+}
+
+#[test]
+fn test_parse_cap_and_batch() {
+    let msgs = [
+        "CAP LS 302",
+        "CAP REQ :batch draft/multiline",
+        "CAP END",
+        "BATCH +abc draft/multiline #room1",
+        "BATCH -abc",
+        "BATCH abc",
+    ];
+
+    let expected = [
+        UserMessage::Cap {
+            subcommand: "LS",
+            args: vec!["302"],
+        },
+        UserMessage::Cap {
+            subcommand: "REQ",
+            args: vec!["batch", "draft/multiline"],
+        },
+        UserMessage::Cap {
+            subcommand: "END",
+            args: vec![],
+        },
+        UserMessage::BatchStart {
+            reference: "abc",
+            batch_type: "draft/multiline",
+            params: vec!["#room1"],
+        },
+        UserMessage::BatchEnd { reference: "abc" },
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_away() {
+    let msgs = ["AWAY :Gone to lunch", "AWAY", "AWAY :"];
+
+    let expected = [
+        UserMessage::Away {
+            message: Some("Gone to lunch"),
+        },
+        UserMessage::Away { message: None },
+        UserMessage::Away { message: None },
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_whois_whowas() {
+    let msgs = [
+        "WHOIS bob",
+        "WHOIS irc.example bob,ana",
+        "WHOIS",
+        "WHOWAS bob 2",
+        "QUIT :Gone fishing",
+    ];
+
+    let expected = [
+        UserMessage::Whois { nicks: vec!["bob"] },
+        UserMessage::Whois {
+            nicks: vec!["bob", "ana"],
+        },
+        UserMessage::Whois { nicks: vec![] },
+        UserMessage::Whowas {
+            nicks: vec!["bob"],
+            count: Some(2),
+        },
+        UserMessage::Quit {
+            quit_msg: Some("Gone fishing"),
+        },
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_user() {
+    let msgs = ["USER bob 0 * :Bob the Builder", "USER bob bob bob bob", "USER bob"];
+
+    let expected = [
+        UserMessage::User {
+            user_name: "bob",
+            host_name: "*",
+            server_name: "0",
+            real_name: "Bob the Builder",
+        },
+        UserMessage::User {
+            user_name: "bob",
+            host_name: "bob",
+            server_name: "bob",
+            real_name: "bob",
+        },
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_who() {
+    let msgs = ["WHO #room1", "WHO b* %tnuhaf,42", "WHO"];
+
+    let expected = [
+        UserMessage::Who {
+            mask: Some("#room1"),
+            options: None,
+        },
+        UserMessage::Who {
+            mask: Some("b*"),
+            options: Some("%tnuhaf,42"),
+        },
+        UserMessage::Who {
+            mask: None,
+            options: None,
+        },
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_list() {
+    let msgs = ["LIST", "LIST #a,>3,C<10", "LIST #a irc.example"];
+
+    let expected = [
+        UserMessage::List { params: vec![] },
+        UserMessage::List {
+            params: vec!["#a", ">3", "C<10"],
+        },
+        UserMessage::List {
+            params: vec!["#a"],
+        },
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_names() {
+    let msgs = ["NAMES", "NAMES #a,#b"];
+
+    let expected = [
+        UserMessage::Names { channels: vec![] },
+        UserMessage::Names {
+            channels: vec!["#a", "#b"],
+        },
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_chunk_reply() {
+    let prefix = ":irc.example 353 bob = #room1";
+    let nicks = (0..100).map(|i| format!("@nickname{:03}", i)).collect::<Vec<_>>();
+
+    let reply = chunk_reply(prefix, nicks.iter().map(|n| n.as_str()));
+    let lines = reply.split_inclusive("\r\n").collect::<Vec<_>>();
+
+    assert!(lines.len() > 1);
+    for line in &lines {
+        assert!(line.len() <= MAX_LINE_LENGTH);
+        assert!(line.starts_with(":irc.example 353 bob = #room1 :@nickname"));
+    }
+
+    let joined = lines
+        .iter()
+        .flat_map(|l| l.trim_end().split(" :").nth(1).unwrap().split(' '))
+        .collect::<Vec<_>>();
+    assert_eq!(nicks, joined);
+
+    assert_eq!("", chunk_reply(prefix, std::iter::empty()));
+
+    let prefix = format!(":irc.example 353 bob = #{}", "a".repeat(500));
+    assert_eq!(
+        format!("{0} :@bob\r\n{0} :ana\r\n", prefix),
+        chunk_reply(&prefix, ["@bob", "ana"].into_iter())
+    );
+}
+
+#[test]
+fn test_parse_kick() {
+    let msgs = [
+        "KICK #room1 bob",
+        "KICK #room1 bob,ana :Be nice",
+        "KICK #a,#b bob,ana",
+        "KICK #room1",
+    ];
+
+    let expected = [
+        UserMessage::Kick {
+            channels: vec!["#room1"],
+            users: vec!["bob"],
+            reason: None,
+        },
+        UserMessage::Kick {
+            channels: vec!["#room1"],
+            users: vec!["bob", "ana"],
+            reason: Some("Be nice"),
+        },
+        UserMessage::Kick {
+            channels: vec!["#a", "#b"],
+            users: vec!["bob", "ana"],
+            reason: None,
+        },
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_invite() {
+    let msgs = ["INVITE bob #room1", "INVITE bob"];
+
+    let expected = [
+        UserMessage::Invite {
+            nick: "bob",
+            channel: "#room1",
+        },
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_mode_changes() {
+    let changes = parse_mode_changes("+iw-o+B");
+
+    assert_eq!(
+        vec![(true, 'i'), (true, 'w'), (false, 'o'), (true, 'B')],
+        changes
+    );
+    assert_eq!("+iw-o+B", format_mode_changes(&changes));
+    assert_eq!("-s", format_mode_changes(&parse_mode_changes("-s")));
+    assert_eq!("", format_mode_changes(&parse_mode_changes("+")));
+}
+
+#[test]
+fn test_parse_oper_commands() {
+    let msgs = [
+        "OPER alice secret",
+        "OPER alice",
+        "KILL bob :Spamming",
+        "KILL bob",
+        "WALLOPS :Server restart soon",
+        "REHASH",
+    ];
+
+    let expected = [
+        UserMessage::Oper {
+            name: "alice",
+            password: "secret",
+        },
+        UserMessage::InvalidMessage,
+        UserMessage::Kill {
+            nick: "bob",
+            reason: "Spamming",
+        },
+        UserMessage::InvalidMessage,
+        UserMessage::Wallops {
+            message: "Server restart soon",
+        },
+        UserMessage::Rehash,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_ban_commands() {
+    let msgs = [
+        "KLINE 30 bob@*.example.com :Spamming",
+        "KLINE bob@*.example.com",
+        "DLINE 0 10.0.0.0/8 :Flood",
+        "GLINE *@evil.org :Go away",
+        "KLINE",
+        "KLINE abc bob@* :reason",
+        "UNKLINE bob@*.example.com",
+        "UNDLINE 10.0.0.0/8",
+        "UNGLINE",
+    ];
+
+    let expected = [
+        UserMessage::Ban {
+            kind: BanKind::K,
+            duration: Some(Duration::from_secs(30 * 60)),
+            mask: "bob@*.example.com",
+            reason: Some("Spamming"),
+        },
+        UserMessage::Ban {
+            kind: BanKind::K,
+            duration: None,
+            mask: "bob@*.example.com",
+            reason: None,
+        },
+        UserMessage::Ban {
+            kind: BanKind::D,
+            duration: None,
+            mask: "10.0.0.0/8",
+            reason: Some("Flood"),
+        },
+        UserMessage::Ban {
+            kind: BanKind::G,
+            duration: None,
+            mask: "*@evil.org",
+            reason: Some("Go away"),
+        },
+        UserMessage::InvalidMessage,
+        UserMessage::InvalidMessage,
+        UserMessage::Unban {
+            kind: BanKind::K,
+            mask: "bob@*.example.com",
+        },
+        UserMessage::Unban {
+            kind: BanKind::D,
+            mask: "10.0.0.0/8",
+        },
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_authenticate() {
+    let msgs = ["AUTHENTICATE EXTERNAL", "AUTHENTICATE +", "AUTHENTICATE"];
+
+    let expected = [
+        UserMessage::Authenticate { data: "EXTERNAL" },
+        UserMessage::Authenticate { data: "+" },
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
+
+#[test]
+fn test_parse_webirc() {
+    let msgs = [
+        "WEBIRC secret gateway user.example.com 192.0.2.1",
+        "WEBIRC secret gateway user.example.com 192.0.2.1 :secure remote-port=5123",
+        "WEBIRC secret gateway 2001:db8::1 ::1 secure",
+        "WEBIRC secret gateway user.example.com",
+    ];
+
+    let expected = [
+        UserMessage::WebIrc {
+            password: "secret",
+            gateway: "gateway",
+            hostname: "user.example.com",
+            ip: "192.0.2.1",
+            flags: vec![],
+        },
+        UserMessage::WebIrc {
+            password: "secret",
+            gateway: "gateway",
+            hostname: "user.example.com",
+            ip: "192.0.2.1",
+            flags: vec!["secure", "remote-port=5123"],
+        },
+        UserMessage::WebIrc {
+            password: "secret",
+            gateway: "gateway",
+            hostname: "2001:db8::1",
+            ip: "::1",
+            flags: vec!["secure"],
+        },
+        UserMessage::InvalidMessage,
+    ];
+
+    assert_messages(&msgs, &expected);
+}
@@ -0,0 +1,127 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[cfg(test)]
+#[path = "./proxy_test.rs"]
+mod proxy_test;
+
+// The longest v1 header, terminator included.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_TYPE_SSL: u8 = 0x20;
+const V2_CLIENT_SSL: u8 = 0x01;
+
+/// What a load balancer tells us about the client with the PROXY protocol.
+#[derive(Debug, PartialEq)]
+pub struct ProxyHeader {
+    // `None` when the balancer connected on its own behalf, e.g. for a
+    // health check, or did not know the address.
+    pub source: Option<SocketAddr>,
+    // The client talked TLS to the balancer.
+    pub tls: bool,
+}
+
+/// Reads a v1 or v2 header from the start of `stream`. Nothing past the
+/// header is consumed, so a TLS handshake or IRC lines can follow.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<ProxyHeader> {
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let mut payload = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut payload).await?;
+
+        return parse_v2(header[0], header[1], &payload);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        bail!("Missing PROXY header");
+    }
+
+    // v1 is read a byte at a time, up to its `\r\n`.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            bail!("PROXY header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line)
+}
+
+// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`,
+// or `PROXY UNKNOWN` followed by anything.
+fn parse_v1(line: &[u8]) -> Result<ProxyHeader> {
+    let line = std::str::from_utf8(line)?
+        .strip_suffix("\r\n")
+        .context("PROXY header is not terminated")?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+
+    let source = match &fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip = source.parse::<IpAddr>()?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                bail!("PROXY address does not match {}", family);
+            }
+            Some(SocketAddr::new(ip, port.parse()?))
+        }
+        _ => bail!("Invalid PROXY header"),
+    };
+
+    Ok(ProxyHeader { source, tls: false })
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> Result<ProxyHeader> {
+    let (source, tlvs) = match (version_command, family) {
+        (V2_LOCAL, _) => return Ok(ProxyHeader { source: None, tls: false }),
+        (V2_PROXY, V2_TCP4) if payload.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[..4])?);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            (Some(SocketAddr::new(ip.into(), port)), &payload[12..])
+        }
+        (V2_PROXY, V2_TCP6) if payload.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[..16])?);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            (Some(SocketAddr::new(ip.into(), port)), &payload[36..])
+        }
+        // Other families, like UDP or Unix sockets, carry no address we
+        // can use.
+        (V2_PROXY, _) => (None, &[][..]),
+        _ => bail!("Invalid PROXY header"),
+    };
+
+    Ok(ProxyHeader {
+        source,
+        tls: client_ssl(tlvs)?,
+    })
+}
+
+// Looks for PP2_TYPE_SSL among the TLVs that follow the addresses. Its first
+// byte has PP2_CLIENT_SSL set when the client used TLS.
+fn client_ssl(mut tlvs: &[u8]) -> Result<bool> {
+    while !tlvs.is_empty() {
+        if tlvs.len() < 3 {
+            bail!("Truncated PROXY TLV");
+        }
+        let length = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        let value = tlvs.get(3..3 + length).context("Truncated PROXY TLV")?;
+
+        if tlvs[0] == V2_TYPE_SSL {
+            return Ok(value.first().is_some_and(|client| client & V2_CLIENT_SSL != 0));
+        }
+        tlvs = &tlvs[3 + length..];
+    }
+
+    Ok(false)
+}
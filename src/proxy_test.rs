@@ -0,0 +1,96 @@
+use super::*;
+
+fn v2(family: u8, addresses: &[u8], tlvs: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend([V2_PROXY, family]);
+    header.extend(((addresses.len() + tlvs.len()) as u16).to_be_bytes());
+    header.extend(addresses);
+    header.extend(tlvs);
+    header
+}
+
+async fn read(mut bytes: &[u8]) -> (Result<ProxyHeader>, Vec<u8>) {
+    let header = read_header(&mut bytes).await;
+    (header, bytes.to_vec())
+}
+
+#[tokio::test]
+async fn test_v1() {
+    let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 6667\r\nNICK bob\r\n").await;
+    assert_eq!(
+        ProxyHeader {
+            source: Some("192.0.2.1:56324".parse().unwrap()),
+            tls: false
+        },
+        header.unwrap()
+    );
+    assert_eq!(b"NICK bob\r\n".to_vec(), rest);
+
+    let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 6667\r\n").await;
+    assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), header.unwrap().source);
+
+    let (header, _) = read(b"PROXY UNKNOWN\r\n").await;
+    assert_eq!(None, header.unwrap().source);
+}
+
+#[tokio::test]
+async fn test_v1_errors() {
+    for header in [
+        &b"NICK bob\r\nUSER bob 0 * :Bob\r\n"[..],
+        b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 6667\r\n",
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+        b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 6667",
+    ] {
+        assert!(read(header).await.0.is_err());
+    }
+
+    let long = format!("PROXY UNKNOWN {}\r\n", "a".repeat(V1_MAX_LENGTH));
+    assert!(read(long.as_bytes()).await.0.is_err());
+}
+
+#[tokio::test]
+async fn test_v2() {
+    let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x1a, 0x0b];
+    let mut bytes = v2(V2_TCP4, &addresses, &[]);
+    bytes.extend(b"NICK bob\r\n");
+
+    let (header, rest) = read(&bytes).await;
+    assert_eq!(
+        ProxyHeader {
+            source: Some("192.0.2.1:56324".parse().unwrap()),
+            tls: false
+        },
+        header.unwrap()
+    );
+    assert_eq!(b"NICK bob\r\n".to_vec(), rest);
+
+    let mut addresses = vec![0x20, 0x01, 0x0d, 0xb8];
+    addresses.resize(15, 0);
+    addresses.push(1);
+    addresses.resize(32, 0);
+    addresses.extend([0xdc, 0x04, 0x1a, 0x0b]);
+    let (header, _) = read(&v2(V2_TCP6, &addresses, &[])).await;
+    assert_eq!(Some("[2001:db8::1]:56324".parse().unwrap()), header.unwrap().source);
+}
+
+#[tokio::test]
+async fn test_v2_tls() {
+    let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x1a, 0x0b];
+    // An unrelated ALPN TLV, then PP2_TYPE_SSL with PP2_CLIENT_SSL set.
+    let tlvs = [0x01, 0, 3, b'i', b'r', b'c', V2_TYPE_SSL, 0, 5, V2_CLIENT_SSL, 0, 0, 0, 0];
+
+    let (header, _) = read(&v2(V2_TCP4, &addresses, &tlvs)).await;
+    assert!(header.unwrap().tls);
+
+    let (header, _) = read(&v2(V2_TCP4, &addresses, &tlvs[..8])).await;
+    assert!(header.is_err());
+}
+
+#[tokio::test]
+async fn test_v2_local() {
+    let mut bytes = V2_SIGNATURE.to_vec();
+    bytes.extend([V2_LOCAL, 0, 0, 0]);
+
+    let (header, _) = read(&bytes).await;
+    assert_eq!(None, header.unwrap().source);
+}
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[cfg(test)]
+#[path = "./sendq_test.rs"]
+mod sendq_test;
+
+pub const DEFAULT_SENDQ: usize = 1024 * 1024;
+
+/// Outgoing queue of a client, bounded by the number of bytes waiting to be
+/// written rather than by the number of lines. Sending never waits on the
+/// recipient: a client whose queue goes over the limit is asked to
+/// disconnect with "SendQ exceeded" and further lines are dropped.
+#[derive(Clone, Debug)]
+pub struct SendQ {
+    sender: UnboundedSender<String>,
+    disconnect: UnboundedSender<String>,
+    queued: Arc<AtomicUsize>,
+    exceeded: Arc<AtomicBool>,
+    limit: usize,
+}
+
+pub struct SendQReceiver {
+    receiver: UnboundedReceiver<String>,
+    queued: Arc<AtomicUsize>,
+}
+
+pub fn sendq(limit: usize, disconnect: UnboundedSender<String>) -> (SendQ, SendQReceiver) {
+    let (sender, receiver) = unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+
+    let sendq = SendQ {
+        sender,
+        disconnect,
+        queued: queued.clone(),
+        exceeded: Arc::new(AtomicBool::new(false)),
+        limit,
+    };
+
+    (sendq, SendQReceiver { receiver, queued })
+}
+
+impl SendQ {
+    pub fn send(&self, message: String) -> Result<()> {
+        if self.exceeded.load(Ordering::Relaxed) {
+            return Err(anyhow!("SendQ exceeded"));
+        }
+
+        let len = message.len();
+        if self.queued.fetch_add(len, Ordering::Relaxed) + len > self.limit {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+            if !self.exceeded.swap(true, Ordering::Relaxed) {
+                let _ = self.disconnect.send("SendQ exceeded".into());
+            }
+            return Err(anyhow!("SendQ exceeded"));
+        }
+
+        if self.sender.send(message).is_err() {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+            return Err(anyhow!("Connection closed"));
+        }
+
+        Ok(())
+    }
+
+    // Queues the closing ERROR line even over the limit, the connection is
+    // dropped once it is written.
+    pub fn close(&self, message: String) -> Result<()> {
+        self.queued.fetch_add(message.len(), Ordering::Relaxed);
+        self.sender.send(message)?;
+        Ok(())
+    }
+}
+
+impl SendQReceiver {
+    pub async fn recv(&mut self) -> Option<String> {
+        let message = self.receiver.recv().await?;
+        self.queued.fetch_sub(message.len(), Ordering::Relaxed);
+        Some(message)
+    }
+
+    pub fn try_recv(&mut self) -> Option<String> {
+        let message = self.receiver.try_recv().ok()?;
+        self.queued.fetch_sub(message.len(), Ordering::Relaxed);
+        Some(message)
+    }
+}
@@ -0,0 +1,37 @@
+use super::*;
+
+#[tokio::test]
+async fn test_queued_bytes() {
+    let (disconnect, _) = unbounded_channel();
+    let (sendq, mut receiver) = sendq(100, disconnect);
+
+    sendq.send("PING :a\r\n".into()).unwrap();
+    sendq.send("PING :bc\r\n".into()).unwrap();
+    assert_eq!(19, sendq.queued.load(Ordering::Relaxed));
+
+    assert_eq!(Some("PING :a\r\n".into()), receiver.recv().await);
+    assert_eq!(10, sendq.queued.load(Ordering::Relaxed));
+    assert_eq!(Some("PING :bc\r\n".into()), receiver.try_recv());
+    assert_eq!(0, sendq.queued.load(Ordering::Relaxed));
+    assert_eq!(None, receiver.try_recv());
+}
+
+#[tokio::test]
+async fn test_sendq_exceeded() {
+    let (disconnect, mut disconnect_receiver) = unbounded_channel();
+    let (sendq, mut receiver) = sendq(10, disconnect);
+
+    sendq.send("123456".into()).unwrap();
+    assert!(sendq.send("123456".into()).is_err());
+    assert_eq!(6, sendq.queued.load(Ordering::Relaxed));
+    assert_eq!(Some("SendQ exceeded".into()), disconnect_receiver.recv().await);
+
+    // Once over the limit the client is on its way out, even if the
+    // queue drains in the meantime, and it is only told to go once.
+    receiver.recv().await;
+    assert!(sendq.send("1".into()).is_err());
+    assert!(disconnect_receiver.try_recv().is_err());
+
+    sendq.close("ERROR :Closing link\r\n".into()).unwrap();
+    assert_eq!(Some("ERROR :Closing link\r\n".into()), receiver.recv().await);
+}
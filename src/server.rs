@@ -1,83 +1,349 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind};
 use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::time::Duration;
 
+use crate::bans::ip_matches;
+use crate::config::{Config, ListenBlock};
 use crate::connections::Connections;
-use crate::messages::parse_message;
-use tokio::io::AsyncBufReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::io::BufReader;
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
+use crate::codec::{Frame, IrcCodec};
+use crate::proxy;
+use crate::sendq::{sendq, SendQReceiver};
+use crate::tls;
+use crate::transport::{Peer, Transport};
+use crate::websocket;
+use futures::future::try_join_all;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{split, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
 use tokio::select;
-use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 #[cfg(test)]
 #[path = "./server_test.rs"]
 mod server_test;
 
+// Time a client gets to send its PROXY header, and again to finish the
+// TLS or WebSocket handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+// Time the writer gets to send what is left, like the closing ERROR, once
+// the client is gone. One that stopped reading does not get to keep it.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// Pause after a failed accept, like one for running out of file
+// descriptors, before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
 pub struct Server {
     pub connections: Connections,
-    pub listener: TcpListener,
+    pub listeners: Vec<Listener>,
 }
 
 impl Server {
-    pub fn new(listener: TcpListener) -> Self {
-        Server {
-            connections: Connections::new(),
-            listener,
+    // Binds every listener from the configuration.
+    pub async fn bind(mut config: Config) -> Result<Self> {
+        let mut listeners = vec![];
+        for listen in config.listeners() {
+            listeners.push(Listener::bind(listen).await?);
         }
+
+        // Port 0 is only resolved once bound, and the STS policy has to
+        // point at the real port.
+        config.listen = listeners.iter().map(|listener| listener.listen.clone()).collect();
+
+        Ok(Server {
+            connections: Connections::with_config(config)?,
+            listeners,
+        })
     }
 
     pub async fn start_server(&mut self) -> Result<()> {
+        let listeners = self.listeners.drain(..);
+        try_join_all(listeners.map(|listener| listener.run(self.connections.clone()))).await?;
+        Ok(())
+    }
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+pub struct Listener {
+    socket: Socket,
+    pub listen: ListenBlock,
+}
+
+impl Listener {
+    pub async fn bind(mut listen: ListenBlock) -> Result<Listener> {
+        let socket = match (listen.address, &listen.path) {
+            (Some(address), _) => {
+                let listener = TcpListener::bind(address)
+                    .await
+                    .with_context(|| format!("Cannot listen on {}", address))?;
+                listen.address = Some(listener.local_addr()?);
+                Socket::Tcp(listener)
+            }
+            (None, Some(path)) => {
+                // A socket file left behind by an earlier run would make
+                // the bind fail. Anything else at the path is not ours.
+                match fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+                    Ok(_) => bail!("Cannot listen on {}: not a socket", path.display()),
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("Cannot listen on {}", path.display()))
+                    }
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Cannot listen on {}", path.display()))?;
+                if let Some(mode) = listen.mode {
+                    fs::set_permissions(path, Permissions::from_mode(mode))?;
+                }
+                Socket::Unix(listener)
+            }
+            (None, None) => bail!("[[listen]] needs either an address or a path"),
+        };
+
+        Ok(Listener { socket, listen })
+    }
+
+    fn transport(&self) -> Transport {
+        match (&self.socket, self.listen.websocket, self.listen.tls) {
+            (_, true, _) => Transport::WebSocket,
+            (_, false, true) => Transport::Tls,
+            (Socket::Unix(_), false, false) => Transport::Unix,
+            (Socket::Tcp(_), false, false) => Transport::Tcp,
+        }
+    }
+
+    async fn run(self, connections: Connections) -> Result<()> {
+        let acceptor = Acceptor::new(connections, self.listen.clone());
+        let transport = self.transport();
+
         loop {
-            let (socket, addr) = self.listener.accept().await?;
-            let reader = BufReader::new(socket);
-
-            tokio::select! {
-                _ = self.new_connection(addr, reader) => {}
-                /*_ = tokio::signal::ctrl_c() => {
-                    return Ok(());
-                }*/
-            };
+            match &self.socket {
+                Socket::Tcp(listener) => {
+                    let (socket, addr) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            accept_failed(e).await;
+                            continue;
+                        }
+                    };
+                    // Only the balancers can speak for someone else.
+                    if !self.listen.proxy.is_empty()
+                        && !self.listen.proxy.iter().any(|mask| ip_matches(mask, addr.ip()))
+                    {
+                        continue;
+                    }
+                    let mut peer = Peer::new(addr, transport);
+                    peer.local = socket.local_addr().ok();
+                    tokio::spawn(connected(acceptor.clone(), socket, peer));
+                }
+                Socket::Unix(listener) => {
+                    let (socket, _) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            accept_failed(e).await;
+                            continue;
+                        }
+                    };
+                    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+                    tokio::spawn(connected(acceptor.clone(), socket, Peer::new(addr, transport)));
+                }
+            }
+        }
+    }
+}
+
+// The error is not the listener's to end on, it would take every other
+// listener down with it.
+async fn accept_failed(e: io::Error) {
+    eprintln!("Cannot accept a connection: {}", e);
+    tokio::time::sleep(ACCEPT_BACKOFF).await;
+}
+
+// Runs in a task of its own for each connection, so a slow client cannot
+// hold up the accept loop. The PROXY header comes first, so that D-lines
+// apply to the real address.
+async fn connected<S>(acceptor: Acceptor, mut stream: S, mut peer: Peer) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if !acceptor.listen.proxy.is_empty() {
+        let header = timeout(HANDSHAKE_TIMEOUT, proxy::read_header(&mut stream)).await??;
+        if let Some(source) = header.source {
+            peer.addr = source;
+            // The connection to ask an ident server about is the one with
+            // the balancer.
+            peer.local = None;
         }
+        peer.secure |= header.tls;
     }
 
-    async fn new_connection(
-        &mut self,
-        addr: SocketAddr,
-        mut reader: BufReader<TcpStream>,
-    ) -> Result<()> {
-        let (sender, mut receiver) = channel::<String>(10);
-        let mut user_connection = self.connections.register_connection(addr, sender)?;
+    if peer.transport != Transport::Unix {
+        if let Some(ban) = acceptor.connections.find_dline(peer.addr.ip()) {
+            // There is no way to tell the client before a TLS or
+            // WebSocket handshake.
+            if peer.transport == Transport::Tcp {
+                let _ = stream
+                    .write_all(
+                        format!("ERROR :Closing link: {} (D-lined: {})\r\n", peer.addr.ip(), ban.reason)
+                            .as_bytes(),
+                    )
+                    .await;
+            }
+            return Ok(());
+        }
+    }
 
-        let future = async move {
-            loop {
-                let mut message = String::new();
+    timeout(HANDSHAKE_TIMEOUT, handshake(acceptor, stream, peer)).await?
+}
+
+// Sets up TLS and the WebSocket as the listener asks for, then hands the
+// stream to the acceptor.
+async fn handshake<S>(acceptor: Acceptor, stream: S, mut peer: Peer) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let listen = &acceptor.listen;
+    if !listen.tls && !listen.websocket {
+        return acceptor.accept(stream, peer);
+    }
+    if !listen.tls {
+        let stream = websocket::accept(stream, &listen.origins).await?;
+        return acceptor.accept(stream, peer);
+    }
+
+    let acceptors = acceptor.connections.tls.read().unwrap().clone();
+    let acceptors = acceptors.context("TLS is not configured")?;
+    let tls_acceptor = if listen.websocket {
+        acceptors.websocket
+    } else {
+        acceptors.irc
+    };
+    let stream = tls_acceptor.accept(stream).await?;
+    peer.secure = true;
+    peer.certfp = tls::peer_fingerprint(stream.get_ref().1);
+
+    if listen.websocket {
+        let stream = websocket::accept(stream, &listen.origins).await?;
+        acceptor.accept(stream, peer)
+    } else {
+        acceptor.accept(stream, peer)
+    }
+}
+
+/// Feeds a byte stream from any transport into the client pipeline, under
+/// the policy of the listener it came from. The listeners hand over their
+/// connections once any handshake is done, and anything else holding a
+/// stream, like an in-memory duplex in tests, can do the same.
+#[derive(Clone)]
+pub struct Acceptor {
+    connections: Connections,
+    listen: ListenBlock,
+}
+
+impl Acceptor {
+    pub fn new(connections: Connections, listen: ListenBlock) -> Self {
+        Acceptor { connections, listen }
+    }
+
+    pub fn accept<S>(&self, mut stream: S, peer: Peer) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if !self.listen.clients {
+            let error = format!(
+                "ERROR :Closing link: {} (Clients are not allowed on this port)\r\n",
+                peer.addr.ip()
+            );
+            tokio::spawn(async move {
+                let _ = stream.write_all(error.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+            return Ok(());
+        }
 
+        let local = peer.local;
+        let mut connections = self.connections.clone();
+        let (disconnect_sender, mut disconnect_receiver) = unbounded_channel::<String>();
+        let (sender, receiver) = sendq(connections.sendq_limit(&self.listen), disconnect_sender.clone());
+        let mut user_connection =
+            connections.register_connection(peer, &self.listen, sender, disconnect_sender)?;
+        let (reader, writer) = split(stream);
+        let mut lines = FramedRead::new(reader, IrcCodec::new());
+        let mut writer = tokio::spawn(write_loop(writer, receiver));
+
+        let reader_future = async move {
+            user_connection.look_up(local).await;
+
+            loop {
                 select! {
-                    from_client = reader.read_line(&mut message) => {
-                        if let Ok(_) = from_client {
-                            if message.trim().len() == 0 {
-                                continue;
+                    from_client = lines.next() => {
+                        match from_client {
+                            None => {
+                                let _ = user_connection.disconnect("Connection closed").await;
+                            }
+                            Some(Err(e)) => {
+                                let _ = user_connection.disconnect(&e.to_string()).await;
+                            }
+                            Some(Ok(Frame::TooLong)) => {
+                                let _ = user_connection.input_too_long().await;
+                            }
+                            Some(Ok(Frame::Line(line))) => {
+                                user_connection.handle_line(line).await;
+                                lines.decoder_mut().set_tags(user_connection.client_tags());
                             }
-                            //println!("{} <-|{}|", addr.to_string(), message.trim());
-                            let msg = parse_message(&message);
-                            let _ = user_connection.handle_message(&msg).await;
                         }
                     },
-                    from_server = receiver.recv() => {
-                        if let Some(to_send) = from_server {
-                            //println!("{} ->|{}|",  addr.to_string(), to_send.trim());
-                            let _ = reader.write(to_send.as_bytes()).await;
-                            let _ = reader.flush().await;
-                        }
-                    }
+                    Some(reason) = disconnect_receiver.recv() => {
+                        let _ = user_connection.disconnect(&reason).await;
+                    },
                 };
+
+                // Dropping the connection releases its SendQ, which lets the
+                // writer finish once everything queued has been sent.
+                if user_connection.is_closed() {
+                    break;
+                }
+            }
+
+            drop(user_connection);
+            if timeout(CLOSE_TIMEOUT, &mut writer).await.is_err() {
+                writer.abort();
             }
         };
 
-        tokio::spawn(future);
+        tokio::spawn(reader_future);
 
         Ok(())
     }
 }
+
+// Feeds everything that is already queued to the codec and writes it with
+// a single flush, instead of one syscall per line.
+async fn write_loop<W: AsyncWrite + Unpin>(writer: W, mut receiver: SendQReceiver) {
+    let mut writer = FramedWrite::new(writer, IrcCodec::new());
+
+    while let Some(to_send) = receiver.recv().await {
+        if writer.feed(to_send).await.is_err() {
+            return;
+        }
+        while let Some(to_send) = receiver.try_recv() {
+            if writer.feed(to_send).await.is_err() {
+                return;
+            }
+        }
+
+        if writer.flush().await.is_err() {
+            return;
+        }
+    }
+
+    let _ = writer.close().await;
+}
@@ -1,13 +1,33 @@
 use std::collections::HashSet;
 
 use super::*;
+use crate::config::ListenBlock;
+use crate::lookup::Resolver;
+use tokio::net::{TcpStream, UnixStream};
+use crate::transport::{Peer, Transport};
 use anyhow::Result;
-use tokio;
+use async_trait::async_trait;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use tokio::io::{duplex, AsyncBufRead, AsyncBufReadExt, AsyncWrite, BufReader, DuplexStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::client_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
 
 #[tokio::test]
 async fn test_connect_to_server() -> Result<()> {
-    let addr = start_server().await.addr;
-    let bob = TcpStream::connect(addr).await.unwrap();
+    let info = start_server().await;
+    let bob = info.connect();
     let mut bob_stream = BufReader::new(bob);
 
     bob_stream.write_all(b"NICK bob\r\n").await?;
@@ -24,11 +44,11 @@ async fn test_connect_to_server() -> Result<()> {
 
 #[tokio::test]
 async fn test_priv_msg() -> Result<()> {
-    let addr = start_server().await.addr;
-    let bob = TcpStream::connect(addr).await.unwrap();
+    let info = start_server().await;
+    let bob = info.connect();
     let mut bob_stream = BufReader::new(bob);
 
-    let alice = TcpStream::connect(addr).await.unwrap();
+    let alice = info.connect();
     let mut alice_stream = BufReader::new(alice);
 
     bob_stream.write_all(b"NICK bob\r\n").await?;
@@ -55,12 +75,11 @@ async fn test_priv_msg() -> Result<()> {
 #[tokio::test]
 async fn test_join_channel() -> Result<()> {
     let info = start_server().await;
-    let addr = info.addr;
 
-    let bob = TcpStream::connect(addr).await.unwrap();
+    let bob = info.connect();
     let mut bob_stream = BufReader::new(bob);
 
-    let joe = TcpStream::connect(addr).await.unwrap();
+    let joe = info.connect();
     let mut joe_stream = BufReader::new(joe);
 
     bob_stream.write_all(b"NICK bob\r\n").await?;
@@ -90,7 +109,7 @@ async fn test_join_channel() -> Result<()> {
 
     let channels = info.connections.channels.lock().await;
 
-    let expected_users = vec!["bob", "joe"]
+    let expected_users = ["bob", "joe"]
         .iter()
         .map(|s| s.to_string())
         .collect::<HashSet<_>>();
@@ -108,12 +127,11 @@ async fn test_join_channel() -> Result<()> {
 #[tokio::test]
 async fn test_send_message_to_channel() -> Result<()> {
     let info = start_server().await;
-    let addr = info.addr;
 
-    let bob = TcpStream::connect(addr).await.unwrap();
+    let bob = info.connect();
     let mut bob_stream = BufReader::new(bob);
 
-    let joe = TcpStream::connect(addr).await.unwrap();
+    let joe = info.connect();
     let mut joe_stream = BufReader::new(joe);
 
     bob_stream.write_all(b"NICK bob\r\n").await?;
@@ -152,27 +170,1946 @@ async fn test_send_message_to_channel() -> Result<()> {
     Ok(())
 }
 
-struct ServerInfo {
-    addr: SocketAddr,
-    connections: Connections,
+#[tokio::test]
+async fn test_multiline_message() -> Result<()> {
+    let info = start_server().await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let joe = info.connect();
+    let mut joe_stream = BufReader::new(joe);
+
+    let ana = info.connect();
+    let mut ana_stream = BufReader::new(ana);
+
+    for (stream, nick) in [
+        (&mut bob_stream, "bob"),
+        (&mut joe_stream, "joe"),
+        (&mut ana_stream, "ana"),
+    ] {
+        if nick != "ana" {
+            stream.write_all(b"CAP LS 302\r\n").await?;
+            read_line(stream).await?;
+            stream
+                .write_all(b"CAP REQ :batch draft/multiline\r\n")
+                .await?;
+            read_line(stream).await?;
+        }
+        stream
+            .write_all(format!("NICK {}\r\nUSER {} a b c\r\n", nick, nick).as_bytes())
+            .await?;
+        if nick != "ana" {
+            stream.write_all(b"CAP END\r\n").await?;
+        }
+        read_line(stream).await?;
+
+        stream.write_all(b"JOIN #room1\r\n").await?;
+        read_line(stream).await?;
+        read_line(stream).await?;
+        read_line(stream).await?;
+    }
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut joe_stream).await?;
+
+    bob_stream
+        .write_all(
+            b"BATCH +m1 draft/multiline #room1\r\n\
+              @batch=m1 PRIVMSG #room1 :fn main() {\r\n\
+              @batch=m1 PRIVMSG #room1 :}\r\n\
+              BATCH -m1\r\n",
+        )
+        .await?;
+
+    let start = read_line(&mut joe_stream).await?;
+    assert!(start.contains("BATCH +"));
+    assert!(start.contains("draft/multiline #room1"));
+    assert!(read_line(&mut joe_stream).await?.contains("PRIVMSG #room1 :fn main() {"));
+    assert!(read_line(&mut joe_stream).await?.contains("PRIVMSG #room1 :}"));
+    assert!(read_line(&mut joe_stream).await?.contains("BATCH -"));
+
+    let first = read_line(&mut ana_stream).await?;
+    assert!(first.starts_with(":bob!bob@"));
+    assert!(first.ends_with("PRIVMSG #room1 :fn main() {\r\n"));
+    assert!(read_line(&mut ana_stream).await?.ends_with("PRIVMSG #room1 :}\r\n"));
+
+    // Lines tagged for a batch that is not open are refused, not dropped.
+    bob_stream.write_all(b"@batch=m1 PRIVMSG #room1 :late\r\n").await?;
+    assert_eq!(
+        ":172.17.0.1 FAIL BATCH INVALID_REFTAG m1 :No such batch is open\r\n",
+        read_line(&mut bob_stream).await?
+    );
+
+    Ok(())
 }
 
-async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<String> {
-    let mut resp = String::new();
-    stream.read_line(&mut resp).await?;
-    dbg!(&resp);
-    return Ok(resp);
+#[tokio::test]
+async fn test_multiline_batch_is_not_a_flood() -> Result<()> {
+    let info = start_server().await;
+
+    let mut streams = Vec::new();
+    for nick in ["bob", "joe"] {
+        let mut stream = BufReader::new(info.connect());
+        stream
+            .write_all(
+                format!(
+                    "CAP REQ :batch draft/multiline\r\nNICK {}\r\nUSER {} a b c\r\nCAP END\r\n",
+                    nick, nick
+                )
+                .as_bytes(),
+            )
+            .await?;
+        read_line(&mut stream).await?;
+        read_line(&mut stream).await?;
+        streams.push(stream);
+    }
+    let [mut bob_stream, mut joe_stream] = streams.try_into().unwrap();
+
+    // Well over the burst, but within max-lines.
+    let mut batch = String::from("BATCH +m1 draft/multiline joe\r\n");
+    for i in 0..100 {
+        batch.push_str(&format!("@batch=m1 PRIVMSG joe :line {}\r\n", i));
+    }
+    batch.push_str("BATCH -m1\r\nPING done\r\n");
+    bob_stream.write_all(batch.as_bytes()).await?;
+
+    assert!(read_line(&mut joe_stream).await?.contains("BATCH +"));
+    for i in 0..100 {
+        assert!(read_line(&mut joe_stream)
+            .await?
+            .ends_with(&format!("PRIVMSG joe :line {}\r\n", i)));
+    }
+    assert!(read_line(&mut joe_stream).await?.contains("BATCH -"));
+    assert!(read_line(&mut bob_stream).await?.ends_with(" done\r\n"));
+
+    Ok(())
 }
 
-async fn start_server() -> ServerInfo {
-    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let addr = listener.local_addr().unwrap();
-    let mut server = Server::new(listener);
-    let connections = server.connections.clone();
+#[tokio::test]
+async fn test_message_source() -> Result<()> {
+    let info = start_server().await;
 
-    tokio::spawn(async move {
-        let _ = server.start_server().await;
-    });
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let joe = info.connect();
+    let mut joe_stream = BufReader::new(joe);
+
+    bob_stream.write_all(b"NICK bob\r\nUSER bobuser bobuser bobuser bobuser\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    joe_stream.write_all(b"NICK joe\r\nUSER joe joe joe joe\r\n").await?;
+    read_line(&mut joe_stream).await?;
+
+    bob_stream.write_all(b"JOIN #room1\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.starts_with(":bob!bobuser@127.0.0.1 "));
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    joe_stream.write_all(b"JOIN #room1\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut joe_stream).await?;
+    assert!(read_line(&mut bob_stream).await?.starts_with(":joe!joe@127.0.0.1 "));
+
+    // The user name goes in the source, and the text keeps a single colon.
+    bob_stream.write_all(b"PRIVMSG #room1 :hi there\r\n").await?;
+    let line = read_line(&mut joe_stream).await?;
+    assert!(line.starts_with(":bob!bobuser@127.0.0.1 "));
+    assert!(line.ends_with(" PRIVMSG #room1 :hi there\r\n"));
+
+    bob_stream.write_all(b"PRIVMSG joe :hello\r\n").await?;
+    let line = read_line(&mut joe_stream).await?;
+    assert!(line.starts_with(":bob!bobuser@127.0.0.1 "));
+    assert!(line.ends_with(" PRIVMSG joe :hello\r\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_away_reply() -> Result<()> {
+    let info = start_server().await;
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    bob_stream.write_all(b"AWAY :Gone to lunch\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 306 bob "));
+
+    alice_stream.write_all(b"PRIVMSG bob :hello\r\n").await?;
+    let away_reply = read_line(&mut alice_stream).await?;
+    assert!(away_reply.contains(" 301 alice bob :Gone to lunch"));
+
+    bob_stream.write_all(b"AWAY\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 305 bob "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_whois() -> Result<()> {
+    let info = start_server().await;
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream
+        .write_all(b"USER bobuser 0 bobhost :Bob\r\n")
+        .await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"JOIN #room1\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"AWAY :brb\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    alice_stream.write_all(b"WHOIS bob\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 311 alice bob bobuser 127.0.0.1 * :Bob"));
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 319 alice bob :@#room1"));
+    assert!(read_line(&mut alice_stream).await?.contains(" 312 alice bob "));
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 301 alice bob :brb"));
+    assert!(read_line(&mut alice_stream).await?.contains(" 317 alice bob "));
+    assert!(read_line(&mut alice_stream).await?.contains(" 318 alice bob "));
+
+    alice_stream.write_all(b"WHOIS nobody\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 401 alice nobody "));
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 318 alice nobody "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_whowas_after_nick_change_and_quit() -> Result<()> {
+    let info = start_server().await;
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream
+        .write_all(b"USER bobuser 0 bobhost :Bob\r\n")
+        .await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    bob_stream.write_all(b"NICK robert\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" NICK :robert"));
+    bob_stream.write_all(b"QUIT :bye\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.starts_with("ERROR "));
+
+    alice_stream.write_all(b"WHOWAS bob\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 314 alice bob bobuser 127.0.0.1 * :Bob"));
+    read_line(&mut alice_stream).await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 369 alice bob "));
+
+    alice_stream.write_all(b"WHOWAS robert\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 314 alice robert "));
+    read_line(&mut alice_stream).await?;
+    read_line(&mut alice_stream).await?;
+
+    alice_stream.write_all(b"WHOIS robert\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 401 alice robert "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_who() -> Result<()> {
+    let info = start_server().await;
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+
+    bob_stream.write_all(b"NICK Bob[m]\r\n").await?;
+    bob_stream
+        .write_all(b"USER bobuser 0 bobhost :Bob\r\n")
+        .await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"JOIN #room1\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"AWAY :brb\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    alice_stream.write_all(b"WHO #room1\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 352 alice #room1 bobuser 127.0.0.1 172.17.0.1 Bob[m] G@ :0 Bob"));
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 315 alice #room1 :End of WHO list"));
+
+    alice_stream.write_all(b"WHO bob{* %tnaf,7\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .ends_with(" 354 alice 7 Bob[m] G 0\r\n"));
+    assert!(read_line(&mut alice_stream).await?.contains(" 315 alice bob{* "));
+
+    // Addresses are only shown for yourself, unless you are an oper.
+    alice_stream.write_all(b"WHO bob{* %ni\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .ends_with(" 354 alice 255.255.255.255 Bob[m]\r\n"));
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"WHO alice %ni\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .ends_with(" 354 alice 127.0.0.1 alice\r\n"));
+    read_line(&mut alice_stream).await?;
+
+    alice_stream.write_all(b"WHO nobody*\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 315 alice nobody* "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list() -> Result<()> {
+    let info = start_server().await;
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    for channel in ["#rust", "#go"] {
+        bob_stream
+            .write_all(format!("JOIN {}\r\n", channel).as_bytes())
+            .await?;
+        read_line(&mut bob_stream).await?;
+        read_line(&mut bob_stream).await?;
+        read_line(&mut bob_stream).await?;
+    }
+    alice_stream.write_all(b"JOIN #rust\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    read_line(&mut alice_stream).await?;
+    read_line(&mut alice_stream).await?;
+
+    alice_stream.write_all(b"LIST >1\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 321 alice "));
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 322 alice #rust 2 :"));
+    assert!(read_line(&mut alice_stream).await?.contains(" 323 alice "));
+
+    alice_stream.write_all(b"LIST #g*\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 322 alice #go 1 :"));
+    assert!(read_line(&mut alice_stream).await?.contains(" 323 alice "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_names_is_split_into_short_lines() -> Result<()> {
+    let info = start_server().await;
+    let mut streams = vec![];
+
+    for i in 0..40 {
+        let stream = info.connect();
+        let mut stream = BufReader::new(stream);
+        let nick = format!("user_with_a_long_nick_{:02}", i);
+
+        stream
+            .write_all(format!("NICK {}\r\nUSER {} 0 * :{}\r\n", nick, nick, nick).as_bytes())
+            .await?;
+        read_line(&mut stream).await?;
+        stream.write_all(b"JOIN #big\r\n").await?;
+        while !read_line(&mut stream).await?.contains(" 366 ") {}
+        streams.push(stream);
+    }
+
+    let stream = &mut streams[0];
+    stream.write_all(b"NAMES #big\r\n").await?;
+
+    let mut names = HashSet::new();
+    loop {
+        let line = read_line(stream).await?;
+        if line.contains(" 366 ") {
+            break;
+        }
+        if !line.contains(" 353 ") {
+            continue;
+        }
+        assert!(line.len() <= 512);
+        assert!(line.contains(" 353 user_with_a_long_nick_00 = #big :"));
+        for name in line.trim_end().split(" :").nth(1).unwrap().split(' ') {
+            names.insert(name.trim_start_matches('@').to_string());
+        }
+    }
+
+    assert_eq!(40, names.len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_names_with_long_channel_name() -> Result<()> {
+    let info = start_server().await;
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\nUSER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    // The 353 prefix alone is longer than a line.
+    let channel = format!("#{}", "a".repeat(499));
+    bob_stream.write_all(format!("JOIN {}\r\n", channel).as_bytes()).await?;
+    while !read_line(&mut bob_stream).await?.contains(" 366 ") {}
+    bob_stream.write_all(format!("NAMES {}\r\n", channel).as_bytes()).await?;
+    assert!(read_line(&mut bob_stream).await?.ends_with(&format!(" 353 bob = {} :@bob\r\n", channel)));
+    assert!(read_line(&mut bob_stream).await?.contains(" 366 "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_kick() -> Result<()> {
+    let info = start_server().await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let joe = info.connect();
+    let mut joe_stream = BufReader::new(joe);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    joe_stream.write_all(b"NICK joe\r\n").await?;
+    joe_stream.write_all(b"USER joe joe joe joe\r\n").await?;
+    read_line(&mut joe_stream).await?;
+
+    bob_stream.write_all(b"JOIN #room1\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+
+    joe_stream.write_all(b"JOIN #room1\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut joe_stream).await?;
+
+    joe_stream.write_all(b"KICK #room1 bob\r\n").await?;
+    assert!(read_line(&mut joe_stream)
+        .await?
+        .contains(" 482 joe #room1 :You're not channel operator"));
+
+    bob_stream.write_all(b"KICK #room1 ana\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" 441 bob ana #room1 "));
+
+    bob_stream
+        .write_all(b"KICK #room1 joe :Be nice\r\n")
+        .await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" KICK #room1 joe :Be nice"));
+    assert!(read_line(&mut joe_stream)
+        .await?
+        .contains(" KICK #room1 joe :Be nice"));
+
+    let channels = info.connections.channels.lock().await;
+    let users = channels.channel_list("#room1").collect::<Vec<_>>();
+    assert_eq!(vec!["bob"], users);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invite_only_channel() -> Result<()> {
+    let info = start_server().await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let joe = info.connect();
+    let mut joe_stream = BufReader::new(joe);
+
+    let ana = info.connect();
+    let mut ana_stream = BufReader::new(ana);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    joe_stream.write_all(b"CAP REQ :invite-notify\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    joe_stream
+        .write_all(b"NICK joe\r\nUSER joe joe joe joe\r\nCAP END\r\n")
+        .await?;
+    read_line(&mut joe_stream).await?;
+
+    ana_stream.write_all(b"NICK ana\r\n").await?;
+    ana_stream.write_all(b"USER ana ana ana ana\r\n").await?;
+    read_line(&mut ana_stream).await?;
+
+    joe_stream.write_all(b"JOIN #room1\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut joe_stream).await?;
+
+    bob_stream.write_all(b"JOIN #room1\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+    read_line(&mut bob_stream).await?;
+
+    joe_stream.write_all(b"MODE #room1 +i\r\n").await?;
+    assert!(read_line(&mut joe_stream).await?.contains(" MODE #room1 +i"));
+    assert!(read_line(&mut bob_stream).await?.contains(" MODE #room1 +i"));
+
+    ana_stream.write_all(b"JOIN #room1\r\n").await?;
+    assert!(read_line(&mut ana_stream)
+        .await?
+        .contains(" 473 ana #room1 :Cannot join channel (+i)"));
+
+    bob_stream.write_all(b"INVITE ana #room1\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" 482 bob #room1 "));
+
+    joe_stream.write_all(b"MODE #room1 -i\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    read_line(&mut bob_stream).await?;
+
+    bob_stream.write_all(b"INVITE ana #room1\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" 341 bob ana #room1"));
+    assert!(read_line(&mut ana_stream)
+        .await?
+        .contains(" INVITE ana :#room1"));
+    assert!(read_line(&mut joe_stream)
+        .await?
+        .ends_with(" INVITE ana #room1\r\n"));
+
+    joe_stream.write_all(b"MODE #room1 +i\r\n").await?;
+    read_line(&mut joe_stream).await?;
+
+    ana_stream.write_all(b"JOIN #room1\r\n").await?;
+    assert!(read_line(&mut ana_stream).await?.contains(" JOIN :#room1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invite_is_not_inherited_with_nick() -> Result<()> {
+    let info = start_server().await;
+
+    let joe = info.connect();
+    let mut joe_stream = BufReader::new(joe);
+    joe_stream.write_all(b"NICK joe\r\nUSER joe joe joe joe\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    joe_stream.write_all(b"JOIN #room1\r\nMODE #room1 +i\r\n").await?;
+    while !read_line(&mut joe_stream).await?.contains(" MODE #room1 +i") {}
+
+    let ana = info.connect();
+    let mut ana_stream = BufReader::new(ana);
+    ana_stream.write_all(b"NICK ana\r\nUSER ana ana ana ana\r\n").await?;
+    read_line(&mut ana_stream).await?;
+
+    joe_stream.write_all(b"INVITE ana #room1\r\n").await?;
+    assert!(read_line(&mut joe_stream).await?.contains(" 341 joe ana #room1"));
+    read_line(&mut ana_stream).await?;
+    ana_stream.write_all(b"NICK anna\r\n").await?;
+    read_line(&mut ana_stream).await?;
+
+    // Someone else taking the old nick gets no invite with it.
+    let eve = info.connect();
+    let mut eve_stream = BufReader::new(eve);
+    eve_stream.write_all(b"NICK ana\r\nUSER eve eve eve eve\r\n").await?;
+    read_line(&mut eve_stream).await?;
+    eve_stream.write_all(b"JOIN #room1\r\n").await?;
+    assert!(read_line(&mut eve_stream).await?.contains(" 473 ana #room1 "));
+
+    ana_stream.write_all(b"JOIN #room1\r\n").await?;
+    assert!(read_line(&mut ana_stream).await?.contains(" JOIN :#room1"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_modes() -> Result<()> {
+    let info = start_server().await;
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    bob_stream.write_all(b"MODE bob +io\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .ends_with("MODE bob :+i\r\n"));
+    bob_stream.write_all(b"MODE bob +x\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 501 bob "));
+    bob_stream.write_all(b"MODE bob\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 221 bob +i"));
+    bob_stream.write_all(b"MODE alice +i\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 502 bob "));
+
+    alice_stream.write_all(b"WHO *\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 352 alice * alice "));
+    assert!(read_line(&mut alice_stream).await?.contains(" 315 alice "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_oper_kill_and_wallops() -> Result<()> {
+    let config = Config::parse(&format!(
+        r#"
+        [[opers]]
+        name = "admin"
+        password = "{}"
+        hosts = ["alice@*"]
+        class = "netadmin"
+
+        [classes.netadmin]
+        privileges = ["kill", "wallops"]
+        "#,
+        bcrypt::hash("secret", 4)?
+    ))?;
+    let info = start_server_with_config(config).await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"MODE bob +w\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    bob_stream.write_all(b"OPER admin secret\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 491 bob "));
+    bob_stream.write_all(b"KILL alice :bye\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 481 bob "));
+
+    alice_stream.write_all(b"OPER admin wrong\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 464 alice "));
+    alice_stream.write_all(b"OPER admin secret\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains("MODE alice :+o"));
+    assert!(read_line(&mut alice_stream).await?.contains(" 381 alice "));
+
+    alice_stream.write_all(b"WALLOPS :Restarting soon\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" WALLOPS :Restarting soon"));
+
+    alice_stream.write_all(b"KILL bob :Spamming\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" KILL bob :Spamming"));
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .starts_with("ERROR :Closing link: 172.17.0.1 (Killed (alice (Spamming)))"));
+
+    alice_stream.write_all(b"WHOIS bob\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 401 alice bob "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_oper_host_from_user_is_not_trusted() -> Result<()> {
+    let config = Config::parse(&format!(
+        r#"
+        [[opers]]
+        name = "admin"
+        password = "{}"
+        hosts = ["*@staff.example.com", "*@127.0.0.2"]
+        class = "admin"
+        "#,
+        bcrypt::hash("secret", 4)?
+    ))?;
+    let info = start_server_with_config(config).await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream
+        .write_all(b"NICK bob\r\nUSER bob 0 staff.example.com :bob\r\n")
+        .await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"OPER admin secret\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 491 bob "));
+
+    // The address is always good to match on.
+    let (client, server) = duplex(64 * 1024);
+    info.acceptor
+        .accept(server, Peer::new("127.0.0.2:4000".parse()?, Transport::Tcp))?;
+    let mut alice_stream = BufReader::new(client);
+    alice_stream.write_all(b"NICK alice\r\nUSER alice 0 * :alice\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"OPER admin secret\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains("MODE alice :+o"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_klines_and_dlines() -> Result<()> {
+    let config = Config::parse(&format!(
+        r#"
+        [[opers]]
+        name = "admin"
+        password = "{}"
+        class = "netadmin"
+
+        [classes.netadmin]
+        privileges = ["kline", "dline"]
+        "#,
+        bcrypt::hash("secret", 4)?
+    ))?;
+    let info = start_server_with_config(config).await;
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"OPER admin secret\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    read_line(&mut alice_stream).await?;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    alice_stream
+        .write_all(b"KLINE 10 bob@* :Spamming\r\n")
+        .await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains("NOTICE alice :Added temporary K-Line 10 min. for [bob@*] (Spamming)"));
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .starts_with("ERROR :Closing link: 172.17.0.1 (K-Line: Spamming)"));
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 465 bob "));
+    assert!(read_line(&mut bob_stream).await?.starts_with("ERROR "));
+
+    alice_stream.write_all(b"UNKLINE bob@*\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains("K-Line for [bob@*] is removed"));
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 001 bob "));
+
+    bob_stream.write_all(b"DLINE 127.0.0.0/8\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 481 bob "));
+
+    alice_stream.write_all(b"DLINE 127.0.0.0/8 :Go away\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains("Added D-Line for [127.0.0.0/8] (Go away)"));
+
+    // D-lines are checked by the listener, before any handshake.
+    let joe = TcpStream::connect(info.addr).await.unwrap();
+    let mut joe_stream = BufReader::new(joe);
+    assert_eq!(
+        "ERROR :Closing link: 127.0.0.1 (D-lined: Go away)\r\n",
+        read_line(&mut joe_stream).await?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_excess_flood() -> Result<()> {
+    let config = Config::parse(
+        r#"
+        [flood]
+        burst = 5
+        rate = 10.0
+        excess = 3
+        "#,
+    )?;
+    let info = start_server_with_config(config).await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    for i in 0..10 {
+        bob_stream
+            .write_all(format!("PING {}\r\n", i).as_bytes())
+            .await?;
+    }
+
+    let mut pongs = 0;
+    loop {
+        let line = read_line(&mut bob_stream).await?;
+        if line.starts_with("ERROR ") {
+            assert_eq!("ERROR :Closing link: 172.17.0.1 (Excess Flood)\r\n", line);
+            break;
+        }
+        assert!(line.ends_with(&format!(" {}\r\n", pongs)));
+        pongs += 1;
+    }
+
+    // Three commands for the burst left after registration, then three
+    // throttled ones before the fourth goes over the limit.
+    assert_eq!(6, pongs);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sendq_exceeded() -> Result<()> {
+    let config = Config::parse(
+        r#"
+        sendq = 1024
+
+        [flood]
+        burst = 100
+        "#,
+    )?;
+    let info = start_server_with_config(config).await;
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+
+    // Every missing nick gets its own 401 reply, which together are well
+    // over the 1024 bytes alice is allowed to have queued.
+    let nicks = (0..40).map(|i| format!("nobody{}", i)).collect::<Vec<_>>();
+    alice_stream
+        .write_all(format!("WHOIS {}\r\n", nicks.join(",")).as_bytes())
+        .await?;
+
+    loop {
+        let line = read_line(&mut alice_stream).await?;
+        assert!(!line.is_empty());
+        if line.starts_with("ERROR ") {
+            assert_eq!("ERROR :Closing link: 172.17.0.1 (SendQ exceeded)\r\n", line);
+            break;
+        }
+    }
+
+    assert!(!info.connections.nicks_map.lock().unwrap().contains_key("alice"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_that_stopped_reading() -> Result<()> {
+    let config = Config::parse(
+        r#"
+        sendq = 8192
+
+        [flood]
+        burst = 1000
+        "#,
+    )?;
+    let info = start_server_with_config(config).await;
+
+    // Little room on the link, so the writer to alice blocks soon.
+    let (mut alice_client, server) = duplex(1024);
+    info.acceptor
+        .accept(server, Peer::new("127.0.0.2:4000".parse()?, Transport::Tcp))?;
+    alice_client
+        .write_all(b"NICK alice\r\nUSER alice 0 * :alice\r\n")
+        .await?;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\nUSER bob 0 * :bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    let text = "x".repeat(400);
+    for _ in 0..200 {
+        bob_stream
+            .write_all(format!("PRIVMSG alice :{}\r\n", text).as_bytes())
+            .await?;
+    }
+    bob_stream.write_all(b"PING done\r\n").await?;
+    let pong = timeout(Duration::from_secs(2), read_line(&mut bob_stream)).await??;
+    assert!(pong.ends_with(" done\r\n"));
+
+    // The link is dropped even though alice never reads what is left.
+    timeout(Duration::from_secs(10), async {
+        while alice_client.write_all(b"PING x\r\n").await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?;
+    assert!(!info.connections.nicks_map.lock().unwrap().contains_key("alice"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_line_split_across_writes() -> Result<()> {
+    let info = start_server().await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK b").await?;
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    bob_stream.write_all(b"ob\r\nUSER bob bob bob bob\r\n").await?;
+
+    assert_eq!(
+        ":172.17.0.1 001 bob :Welcome to the Internet Relay Network, bob!\r\n",
+        read_line(&mut bob_stream).await?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_input_too_long() -> Result<()> {
+    let info = start_server().await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    bob_stream
+        .write_all(format!("PRIVMSG bob :{}\r\nPING 1\r\n", "a".repeat(600)).as_bytes())
+        .await?;
+    assert_eq!(
+        ":172.17.0.1 417 bob :Input line was too long\r\n",
+        read_line(&mut bob_stream).await?
+    );
+    assert!(read_line(&mut bob_stream).await?.ends_with(" 1\r\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_non_utf8_input() -> Result<()> {
+    let info = start_server().await;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    bob_stream.write_all(b"PRIVMSG bob :caf\xe9\r\n").await?;
+    assert_eq!(
+        ":172.17.0.1 NOTICE bob :Your message was dropped because it is not valid UTF-8\r\n",
+        read_line(&mut bob_stream).await?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_charset_fallback() -> Result<()> {
+    let config = Config::parse(r#"charset = "cp1252""#)?;
+    let info = start_server_with_config(config).await;
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"JOIN #rust\r\n").await?;
+    while !read_line(&mut alice_stream).await?.contains(" 366 ") {}
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"JOIN #rust\r\n").await?;
+    while !read_line(&mut bob_stream).await?.contains(" 366 ") {}
+    read_line(&mut alice_stream).await?;
+
+    alice_stream
+        .write_all(b"PRIVMSG #rust :caf\xe9 \x80\r\n")
+        .await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .ends_with(" PRIVMSG #rust :café €\r\n"));
+
+    alice_stream.write_all(b"MODE #rust +U\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" MODE #rust +U"));
+    assert!(read_line(&mut bob_stream).await?.contains(" MODE #rust +U"));
+
+    alice_stream.write_all(b"PRIVMSG #rust :caf\xe9\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 404 alice #rust :Cannot send to channel"));
+
+    alice_stream.write_all("PRIVMSG #rust :café\r\n".as_bytes()).await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .ends_with(" PRIVMSG #rust :café\r\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_listener() -> Result<()> {
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("avalon-server-{}.crt", std::process::id()));
+    let key = dir.join(format!("avalon-server-{}.key", std::process::id()));
+    let config_path = dir.join(format!("avalon-server-{}.toml", std::process::id()));
+    let certificate = write_certificate(&cert, &key);
+
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+            [tls]
+            cert = "{}"
+            key = "{}"
+
+            # REHASH reads this again.
+            [lookup]
+            dns = false
+
+            [[opers]]
+            name = "admin"
+            password = "{}"
+            class = "admin"
+
+            [classes.admin]
+            privileges = ["rehash"]
+            "#,
+            cert.display(),
+            key.display(),
+            bcrypt::hash("secret", 4)?
+        ),
+    )?;
+    let info = start_server_with_config(Config::load(&config_path)?).await;
+    let tls_addr = info.tls_addr.unwrap();
+
+    let mut alice_stream = connect_tls(tls_addr, certificate.clone(), None).await?;
+    assert_eq!(Some(&b"irc"[..]), alice_stream.get_ref().get_ref().1.alpn_protocol());
+    alice_stream.write_all(b"NICK alice\r\n").await?;
+    alice_stream
+        .write_all(b"USER alice alice alice alice\r\n")
+        .await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 001 alice "));
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\n").await?;
+    bob_stream.write_all(b"USER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    for (target, secure) in [("alice", true), ("bob", false)] {
+        bob_stream
+            .write_all(format!("WHOIS {}\r\n", target).as_bytes())
+            .await?;
+        let mut found = false;
+        loop {
+            let line = read_line(&mut bob_stream).await?;
+            found |= line.contains(&format!(" 671 bob {} :is using a secure connection", target));
+            if line.contains(" 318 ") {
+                break;
+            }
+        }
+        assert_eq!(secure, found);
+    }
+
+    // A new certificate is picked up by REHASH, without dropping alice.
+    let new_certificate = write_certificate(&cert, &key);
+    alice_stream.write_all(b"OPER admin secret\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"REHASH\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 382 alice "));
+    alice_stream.write_all(b"PING 1\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.ends_with(" 1\r\n"));
+
+    assert!(connect_tls(tls_addr, certificate, None).await.is_err());
+    let mut joe_stream = connect_tls(tls_addr, new_certificate, None).await?;
+    joe_stream.write_all(b"NICK joe\r\n").await?;
+    joe_stream.write_all(b"USER joe joe joe joe\r\n").await?;
+    assert!(read_line(&mut joe_stream).await?.contains(" 001 joe "));
+
+    for path in [cert, key, config_path] {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_certfp() -> Result<()> {
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("avalon-certfp-{}.crt", std::process::id()));
+    let key = dir.join(format!("avalon-certfp-{}.key", std::process::id()));
+    let certificate = write_certificate(&cert, &key);
+
+    let alice = rcgen::generate_simple_self_signed(vec!["alice".into()])?;
+    let alice_fp = tls::fingerprint(alice.cert.der());
+    let joe = rcgen::generate_simple_self_signed(vec!["joe".into()])?;
+    let joe_fp = tls::fingerprint(joe.cert.der());
+
+    let config: Config = toml::from_str(&format!(
+        r#"
+        kline_exempt = ["{alice_fp}"]
+
+        [tls]
+        cert = "{}"
+        key = "{}"
+
+        [[accounts]]
+        name = "alice"
+        certfp = ["{alice_fp}"]
+
+        [[opers]]
+        name = "joe"
+        certfp = "{joe_fp}"
+        class = "admin"
+
+        [classes.admin]
+        privileges = ["kline"]
+        "#,
+        cert.display(),
+        key.display(),
+    ))?;
+    let info = start_server_with_config(config).await;
+    let tls_addr = info.tls_addr.unwrap();
+
+    // SASL EXTERNAL logs alice in with her certificate.
+    let mut alice_stream = connect_tls(tls_addr, certificate.clone(), Some(&alice)).await?;
+    alice_stream.write_all(b"CAP LS 302\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains("sasl=EXTERNAL"));
+    alice_stream.write_all(b"CAP REQ :sasl\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" ACK :sasl"));
+    alice_stream.write_all(b"NICK alice\r\nUSER alice alice alice alice\r\n").await?;
+    alice_stream.write_all(b"AUTHENTICATE EXTERNAL\r\n").await?;
+    assert_eq!("AUTHENTICATE +\r\n", read_line(&mut alice_stream).await?);
+    alice_stream.write_all(b"AUTHENTICATE +\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains(" 900 alice alice!alice@127.0.0.1 alice :"));
+    assert!(read_line(&mut alice_stream).await?.contains(" 903 alice :"));
+    alice_stream.write_all(b"CAP END\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 001 alice "));
+    alice_stream.write_all(b"MODE alice\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" 221 alice +r"));
+
+    // A plaintext client has no certificate to log in with.
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"CAP REQ :sasl\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"AUTHENTICATE PLAIN\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 908 * EXTERNAL :"));
+    assert!(read_line(&mut bob_stream).await?.contains(" 904 * :"));
+    bob_stream.write_all(b"AUTHENTICATE EXTERNAL\r\n").await?;
+    read_line(&mut bob_stream).await?;
+    bob_stream.write_all(b"AUTHENTICATE +\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 904 * :"));
+    bob_stream.write_all(b"CAP END\r\nNICK bob\r\nUSER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    // The fingerprint is only shown to its owner and to operators.
+    let certfp_line = format!(" 276 alice alice :has client certificate fingerprint {}", alice_fp);
+    let lines = whois(&mut alice_stream, "alice").await?;
+    assert!(lines.iter().any(|line| line.contains(&certfp_line)));
+    assert!(lines.iter().any(|line| line.contains(" 330 alice alice alice ")));
+    let lines = whois(&mut bob_stream, "alice").await?;
+    assert!(!lines.iter().any(|line| line.contains(" 276 ")));
+
+    // joe's oper block has no password, only his certificate, so any
+    // password will do.
+    let mut joe_stream = connect_tls(tls_addr, certificate.clone(), Some(&joe)).await?;
+    joe_stream.write_all(b"NICK joe\r\nUSER joe joe joe joe\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    bob_stream.write_all(b"OPER joe *\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 491 bob "));
+    joe_stream.write_all(b"OPER joe *\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    assert!(read_line(&mut joe_stream).await?.contains(" 381 joe "));
+    let lines = whois(&mut joe_stream, "alice").await?;
+    assert!(lines.iter().any(|line| line.contains(" 276 joe alice ")));
+
+    // alice is exempt from K-lines, bob is not.
+    joe_stream.write_all(b"KLINE *@* :everyone\r\n").await?;
+    read_line(&mut joe_stream).await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .starts_with("ERROR :Closing link"));
+    alice_stream.write_all(b"PING 1\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.ends_with(" 1\r\n"));
+
+    for path in [cert, key] {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_secure_only_channel() -> Result<()> {
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("avalon-sts-{}.crt", std::process::id()));
+    let key = dir.join(format!("avalon-sts-{}.key", std::process::id()));
+    let certificate = write_certificate(&cert, &key);
+
+    let config: Config = toml::from_str(&format!(
+        r#"
+        [tls]
+        cert = "{}"
+        key = "{}"
+        sts_duration = 86400
+        "#,
+        cert.display(),
+        key.display(),
+    ))?;
+    let info = start_server_with_config(config).await;
+
+    // The plaintext listener points clients at the TLS port.
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"CAP LS\r\n").await?;
+    let sts = format!(" sts=port={},duration=86400\r\n", info.tls_addr.unwrap().port());
+    assert!(read_line(&mut bob_stream).await?.ends_with(&sts));
+    bob_stream.write_all(b"CAP END\r\nNICK bob\r\nUSER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    let mut alice_stream = connect_tls(info.tls_addr.unwrap(), certificate.clone(), None).await?;
+    alice_stream.write_all(b"CAP LS 302\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .ends_with(" sts=duration=86400\r\n"));
+    alice_stream.write_all(b"CAP END\r\nNICK alice\r\nUSER alice alice alice alice\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"JOIN #secret\r\n").await?;
+    while !read_line(&mut alice_stream).await?.contains(" 366 ") {}
+    alice_stream.write_all(b"MODE #secret +z\r\n").await?;
+    assert!(read_line(&mut alice_stream).await?.contains(" MODE #secret +z"));
+
+    bob_stream.write_all(b"JOIN #secret\r\n").await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .contains(" 489 bob #secret :Cannot join channel (+z)"));
+
+    let mut carol_stream = connect_tls(info.tls_addr.unwrap(), certificate, None).await?;
+    carol_stream.write_all(b"NICK carol\r\nUSER carol carol carol carol\r\n").await?;
+    read_line(&mut carol_stream).await?;
+    carol_stream.write_all(b"JOIN #secret\r\n").await?;
+    assert!(read_line(&mut carol_stream).await?.contains(" JOIN :#secret"));
+
+    for path in [cert, key] {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket() -> Result<()> {
+    let config = Config::parse(
+        r#"
+        [[listen]]
+        address = "127.0.0.1:0"
+
+        [[listen]]
+        address = "127.0.0.1:0"
+        websocket = true
+        origins = ["https://web.example.com"]
+        "#,
+    )?;
+    let info = start_server_with_config(config).await;
+    let websocket_addr = info.websocket_addr.unwrap();
+
+    let request = |origin: &'static str| {
+        let mut request = format!("ws://{}/", websocket_addr).into_client_request().unwrap();
+        let headers = request.headers_mut();
+        headers.insert("Origin", HeaderValue::from_static(origin));
+        headers.insert("Sec-WebSocket-Protocol", HeaderValue::from_static("text.ircv3.net"));
+        request
+    };
+
+    let stream = TcpStream::connect(websocket_addr).await?;
+    assert!(client_async(request("https://evil.example.org"), stream).await.is_err());
+
+    let stream = TcpStream::connect(websocket_addr).await?;
+    let (mut alice, response) = client_async(request("https://web.example.com"), stream).await?;
+    assert_eq!("text.ircv3.net", response.headers()["Sec-WebSocket-Protocol"]);
+    alice.send(Message::text("NICK alice")).await?;
+    alice.send(Message::text("USER alice alice alice alice")).await?;
+    let welcome = alice.next().await.unwrap()?;
+    assert!(welcome.to_text()?.contains(" 001 alice "));
+    assert!(!welcome.to_text()?.ends_with("\n"));
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"NICK bob\r\nUSER bob bob bob bob\r\n").await?;
+    read_line(&mut bob_stream).await?;
+
+    bob_stream.write_all(b"PRIVMSG alice :hi\r\n").await?;
+    assert!(alice
+        .next()
+        .await
+        .unwrap()?
+        .to_text()?
+        .ends_with(" PRIVMSG alice :hi"));
+    alice.send(Message::text("PRIVMSG bob :hello")).await?;
+    assert!(read_line(&mut bob_stream)
+        .await?
+        .ends_with(" PRIVMSG bob :hello\r\n"));
+
+    // Closing the WebSocket quits alice.
+    alice.close(None).await?;
+    while let Some(Ok(_)) = alice.next().await {}
+    bob_stream.write_all(b"WHOIS alice\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 401 bob alice "));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket_over_tls() -> Result<()> {
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("avalon-wss-{}.crt", std::process::id()));
+    let key = dir.join(format!("avalon-wss-{}.key", std::process::id()));
+    let certificate = write_certificate(&cert, &key);
+    let config = Config::parse(&format!(
+        r#"
+        [tls]
+        cert = "{}"
+        key = "{}"
+
+        [[listen]]
+        address = "127.0.0.1:0"
+
+        [[listen]]
+        address = "127.0.0.1:0"
+        tls = true
+        websocket = true
+        "#,
+        cert.display(),
+        key.display(),
+    ))?;
+    let info = start_server_with_config(config).await;
+    let websocket_addr = info.websocket_addr.unwrap();
+
+    // A browser offers HTTP, not IRC, in its ClientHello.
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate)?;
+    let mut tls_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let stream = TcpStream::connect(websocket_addr).await?;
+    let stream = TlsConnector::from(Arc::new(tls_config))
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+    assert_eq!(Some(&b"http/1.1"[..]), stream.get_ref().1.alpn_protocol());
+
+    let (mut alice, _) = client_async("wss://localhost/", stream).await?;
+    alice.send(Message::text("NICK alice")).await?;
+    alice.send(Message::text("USER alice alice alice alice")).await?;
+    let welcome = alice.next().await.unwrap()?;
+    assert!(welcome.to_text()?.contains(" 001 alice "));
+
+    for path in [cert, key] {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listeners() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("avalon-{}.sock", std::process::id()));
+    let config = Config::parse(&format!(
+        r#"
+        [connect_classes.bots]
+        sendq = 4194304
+
+        [[listen]]
+        address = "127.0.0.1:0"
+        password = "{}"
+
+        [[listen]]
+        address = "127.0.0.1:0"
+        clients = false
+
+        [[listen]]
+        path = "{}"
+        mode = 0o600
+        class = "bots"
+        "#,
+        bcrypt::hash("letmein", 4)?,
+        path.display()
+    ))?;
+    let info = start_server_with_config(config).await;
+
+    // A local bot over the Unix socket.
+    assert_eq!(0o600, std::fs::metadata(&path)?.permissions().mode() & 0o777);
+    let bot = UnixStream::connect(&path).await?;
+    let mut bot_stream = BufReader::new(bot);
+    bot_stream.write_all(b"NICK bot\r\nUSER bot bot bot bot\r\n").await?;
+    assert!(read_line(&mut bot_stream).await?.contains(" 001 bot "));
+    let lines = whois(&mut bot_stream, "bot").await?;
+    assert!(lines.iter().any(|line| line.contains(" 671 bot bot ")));
+
+    // The TCP listener wants a password.
+    let bob = TcpStream::connect(info.addr).await?;
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"PASS wrong\r\nNICK bob\r\nUSER bob bob bob bob\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 464 bob :Password incorrect"));
+    assert!(read_line(&mut bob_stream).await?.starts_with("ERROR "));
+
+    let bob = TcpStream::connect(info.addr).await?;
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"PASS letmein\r\nNICK bob\r\nUSER bob bob bob bob\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 001 bob "));
+    bob_stream.write_all(b"PASS letmein\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 462 bob "));
+
+    let closed = info.connections.config.read().unwrap().listen[1].address.unwrap();
+    let joe = TcpStream::connect(closed).await?;
+    let mut joe_stream = BufReader::new(joe);
+    assert_eq!(
+        "ERROR :Closing link: 127.0.0.1 (Clients are not allowed on this port)\r\n",
+        read_line(&mut joe_stream).await?
+    );
+
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unix_listener_keeps_other_files() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("avalon-{}.notasock", std::process::id()));
+    std::fs::write(&path, "keep me")?;
+
+    let listen = ListenBlock {
+        path: Some(path.clone()),
+        ..ListenBlock::default()
+    };
+    assert!(Listener::bind(listen.clone()).await.is_err());
+    assert_eq!("keep me", std::fs::read_to_string(&path)?);
+
+    // A socket left behind is replaced.
+    std::fs::remove_file(&path)?;
+    drop(std::os::unix::net::UnixListener::bind(&path)?);
+    assert!(Listener::bind(listen).await.is_ok());
+
+    std::fs::remove_file(path)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_proxy_protocol() -> Result<()> {
+    let config = Config::parse(&format!(
+        r#"
+        [[opers]]
+        name = "admin"
+        password = "{}"
+        class = "netadmin"
+
+        [classes.netadmin]
+        privileges = ["dline"]
+
+        [[listen]]
+        address = "127.0.0.1:0"
+
+        [[listen]]
+        address = "127.0.0.1:0"
+        proxy = ["127.0.0.0/8"]
+
+        [[listen]]
+        address = "127.0.0.1:0"
+        proxy = ["192.0.2.0/24"]
+        "#,
+        bcrypt::hash("secret", 4)?
+    ))?;
+    let info = start_server_with_config(config).await;
+    let (proxied, untrusted) = {
+        let config = info.connections.config.read().unwrap();
+        (config.listen[1].address.unwrap(), config.listen[2].address.unwrap())
+    };
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+    alice_stream.write_all(b"NICK alice\r\nUSER alice alice alice alice\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"OPER admin secret\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    read_line(&mut alice_stream).await?;
+
+    // The balancer's address is replaced by the one in the header.
+    let bob = TcpStream::connect(proxied).await?;
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream
+        .write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 41000 6667\r\nNICK bob\r\nUSER bob bob bob bob\r\n")
+        .await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 001 bob "));
+    bob_stream.write_all(b"JOIN #proxy\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.starts_with(":bob!bob@192.0.2.1 JOIN "));
+
+    // A v2 header saying the client used TLS with the balancer.
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x11".to_vec();
+    header.extend_from_slice(&[192, 0, 2, 2, 127, 0, 0, 1, 0xa0, 0x28, 0x1a, 0x0b]);
+    header.extend_from_slice(&[0x20, 0x00, 0x02, 0x01, 0x00]);
+    let carol = TcpStream::connect(proxied).await?;
+    let mut carol_stream = BufReader::new(carol);
+    carol_stream.write_all(&header).await?;
+    carol_stream.write_all(b"NICK carol\r\nUSER carol carol carol carol\r\n").await?;
+    assert!(read_line(&mut carol_stream).await?.contains(" 001 carol "));
+    let lines = whois(&mut carol_stream, "carol").await?;
+    assert!(lines.iter().any(|line| line.contains(" 671 carol carol ")));
+
+    // D-lines apply to the client, not the balancer.
+    alice_stream.write_all(b"DLINE 192.0.2.0/24 :Go away\r\n").await?;
+    assert!(read_line(&mut alice_stream)
+        .await?
+        .contains("Added D-Line for [192.0.2.0/24] (Go away)"));
+    let joe = TcpStream::connect(proxied).await?;
+    let mut joe_stream = BufReader::new(joe);
+    joe_stream.write_all(b"PROXY TCP4 192.0.2.3 127.0.0.1 41000 6667\r\n").await?;
+    assert_eq!(
+        "ERROR :Closing link: 192.0.2.3 (D-lined: Go away)\r\n",
+        read_line(&mut joe_stream).await?
+    );
+
+    // Connections that do not come from a balancer are dropped.
+    let joe = TcpStream::connect(untrusted).await?;
+    let mut joe_stream = BufReader::new(joe);
+    assert_eq!("", read_line(&mut joe_stream).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_webirc() -> Result<()> {
+    let config = Config::parse(&format!(
+        r#"
+        [[opers]]
+        name = "admin"
+        password = "{0}"
+        class = "netadmin"
+
+        [classes.netadmin]
+        privileges = ["dline"]
+
+        [[webirc]]
+        password = "{0}"
+        hosts = ["127.0.0.0/8"]
+        "#,
+        bcrypt::hash("secret", 4)?
+    ))?;
+    let info = start_server_with_config(config).await;
+
+    let alice = info.connect();
+    let mut alice_stream = BufReader::new(alice);
+    alice_stream.write_all(b"NICK alice\r\nUSER alice alice alice alice\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    alice_stream.write_all(b"OPER admin secret\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    read_line(&mut alice_stream).await?;
+
+    let bob = info.connect();
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream.write_all(b"WEBIRC wrong gateway user.example.com 198.51.100.7\r\n").await?;
+    assert_eq!(
+        "ERROR :Closing link: 172.17.0.1 (Invalid WEBIRC gateway)\r\n",
+        read_line(&mut bob_stream).await?
+    );
+
+    // The host from the gateway sticks, whatever USER says.
+    let (bob, server) = duplex(64 * 1024);
+    info.acceptor
+        .accept(server, Peer::new("127.0.0.1:4000".parse()?, Transport::Tls))?;
+    let mut bob_stream = BufReader::new(bob);
+    bob_stream
+        .write_all(b"WEBIRC secret gateway user.example.com 198.51.100.7 :secure\r\nNICK bob\r\nUSER bob bob bob bob\r\n")
+        .await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 001 bob "));
+    let lines = whois(&mut bob_stream, "bob").await?;
+    assert!(lines[0].contains(" 311 bob bob bob user.example.com * "));
+    assert!(lines.iter().any(|line| line.contains(" 671 bob bob ")));
+    bob_stream.write_all(b"WEBIRC secret gateway user.example.com 198.51.100.8\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains(" 462 bob "));
+    bob_stream.write_all(b"JOIN #webirc\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.starts_with(":bob!bob@user.example.com JOIN "));
+
+    // The secure flag means nothing over a plaintext link to the gateway.
+    let carol = info.connect();
+    let mut carol_stream = BufReader::new(carol);
+    carol_stream
+        .write_all(b"WEBIRC secret gateway bad/host ::1 :secure\r\nNICK carol\r\nUSER carol carol carol carol\r\n")
+        .await?;
+    assert!(read_line(&mut carol_stream).await?.contains(" 001 carol "));
+    let lines = whois(&mut carol_stream, "carol").await?;
+    assert!(lines[0].contains(" 311 carol carol carol 0::1 * "));
+    assert!(!lines.iter().any(|line| line.contains(" 671 ")));
+
+    alice_stream.write_all(b"DLINE 198.51.100.0/24 :Go away\r\n").await?;
+    read_line(&mut alice_stream).await?;
+    let joe = info.connect();
+    let mut joe_stream = BufReader::new(joe);
+    joe_stream.write_all(b"WEBIRC secret gateway user.example.com 198.51.100.9\r\n").await?;
+    assert_eq!(
+        "ERROR :Closing link: 172.17.0.1 (D-lined: Go away)\r\n",
+        read_line(&mut joe_stream).await?
+    );
+
+    Ok(())
+}
+
+// Knows one client by name, and runs an ident server for it.
+struct StandInResolver;
+
+#[async_trait]
+impl Resolver for StandInResolver {
+    async fn reverse(&self, ip: IpAddr) -> io::Result<Option<String>> {
+        Ok(match ip.to_string().as_str() {
+            "192.0.2.1" => Some("client.example.com".into()),
+            "192.0.2.2" => Some("spoofed.example.com".into()),
+            _ => None,
+        })
+    }
+
+    async fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Ok(match host {
+            "client.example.com" => vec!["192.0.2.1".parse().unwrap()],
+            "spoofed.example.com" => vec!["198.51.100.1".parse().unwrap()],
+            _ => vec![],
+        })
+    }
+
+    async fn ident(&self, remote: SocketAddr, local: SocketAddr) -> io::Result<String> {
+        match remote.ip().to_string().as_str() {
+            "192.0.2.1" => Ok(format!("{}, {} : USERID : UNIX : alice", remote.port(), local.port())),
+            _ => Err(io::ErrorKind::ConnectionRefused.into()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_lookups() -> Result<()> {
+    let config = Config::parse(&format!(
+        r#"
+        [lookup]
+        ident = true
+
+        [[opers]]
+        name = "admin"
+        password = "{}"
+        hosts = ["alice@client.example.com"]
+        class = "admin"
+        "#,
+        bcrypt::hash("secret", 4)?
+    ))?;
+    let info = start_server_with_resolver(config, Some(Arc::new(StandInResolver))).await;
+    let connect = |addr: &str| {
+        let (client, server) = duplex(64 * 1024);
+        let mut peer = Peer::new(addr.parse().unwrap(), Transport::Tcp);
+        peer.local = Some("192.0.2.100:6667".parse().unwrap());
+        info.acceptor.accept(server, peer).unwrap();
+        BufReader::new(client)
+    };
+    // Everything up to and including 001.
+    async fn register<S: AsyncBufRead + AsyncWrite + Unpin>(stream: &mut S, nick: &str) -> Result<Vec<String>> {
+        stream
+            .write_all(format!("NICK {0}\r\nUSER {0} 0 fake.example.org :{0}\r\n", nick).as_bytes())
+            .await?;
+        let mut lines = vec![];
+        loop {
+            let line = read_line(stream).await?;
+            lines.push(line.clone());
+            if line.contains(" 001 ") {
+                return Ok(lines);
+            }
+        }
+    }
+
+    // The ident server says bob is alice.
+    let mut bob_stream = connect("192.0.2.1:40000");
+    let lines = register(&mut bob_stream, "bob").await?;
+    for notice in [
+        "Looking up your hostname...",
+        "Found your hostname",
+        "Checking Ident",
+        "Got Ident response",
+    ] {
+        let notice = format!(":172.17.0.1 NOTICE AUTH :*** {}\r\n", notice);
+        assert!(lines.contains(&notice));
+    }
+    let lines = whois(&mut bob_stream, "bob").await?;
+    assert!(lines[0].contains(" 311 bob bob alice client.example.com * "));
+    bob_stream.write_all(b"OPER admin secret\r\n").await?;
+    assert!(read_line(&mut bob_stream).await?.contains("MODE bob :+o"));
+
+    // A name that does not resolve back is not taken, and neither is the
+    // host from USER.
+    let mut carol_stream = connect("192.0.2.2:40000");
+    let lines = register(&mut carol_stream, "carol").await?;
+    assert!(lines.contains(&":172.17.0.1 NOTICE AUTH :*** Couldn't look up your hostname\r\n".into()));
+    assert!(lines.contains(&":172.17.0.1 NOTICE AUTH :*** No Ident response\r\n".into()));
+    let lines = whois(&mut carol_stream, "carol").await?;
+    assert!(lines[0].contains(" 311 carol carol carol 192.0.2.2 * "));
+
+    let mut joe_stream = connect("[2001:db8::1]:40000");
+    register(&mut joe_stream, "joe").await?;
+    let lines = whois(&mut joe_stream, "joe").await?;
+    assert!(lines[0].contains(" 311 joe joe joe 2001:db8::1 * "));
+
+    Ok(())
+}
+
+struct ServerInfo {
+    addr: SocketAddr,
+    tls_addr: Option<SocketAddr>,
+    websocket_addr: Option<SocketAddr>,
+    connections: Connections,
+    acceptor: Acceptor,
+}
+
+impl ServerInfo {
+    // A plaintext client over an in-memory stream, each on a loopback port
+    // of its own like clients over TCP.
+    fn connect(&self) -> DuplexStream {
+        static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+        let (client, server) = duplex(64 * 1024);
+        let addr = SocketAddr::from(([127, 0, 0, 1], NEXT_PORT.fetch_add(1, Ordering::Relaxed)));
+        self.acceptor.accept(server, Peer::new(addr, Transport::Tcp)).unwrap();
+
+        client
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(stream: &mut R) -> Result<String> {
+    let mut resp = String::new();
+    stream.read_line(&mut resp).await?;
+    dbg!(&resp);
+    Ok(resp)
+}
+
+// Sends WHOIS and returns the replies before 318.
+async fn whois<S: AsyncBufRead + AsyncWrite + Unpin>(stream: &mut S, target: &str) -> Result<Vec<String>> {
+    stream.write_all(format!("WHOIS {}\r\n", target).as_bytes()).await?;
+    let mut lines = vec![];
+    loop {
+        let line = read_line(stream).await?;
+        if line.contains(" 318 ") {
+            return Ok(lines);
+        }
+        lines.push(line);
+    }
+}
+
+async fn start_server() -> ServerInfo {
+    start_server_with_config(Config::default()).await
+}
+
+// Clients are not looked up unless a test brings its own resolver, so that
+// nothing goes out to the network.
+async fn start_server_with_config(mut config: Config) -> ServerInfo {
+    config.lookup.dns = false;
+    config.lookup.ident = false;
+    start_server_with_resolver(config, None).await
+}
+
+// Listens on loopback, plus TLS when it is configured, unless the config
+// has listeners of its own.
+async fn start_server_with_resolver(mut config: Config, resolver: Option<Arc<dyn Resolver>>) -> ServerInfo {
+    if config.listen.is_empty() {
+        let address = Some(SocketAddr::from(([127, 0, 0, 1], 0)));
+        config.listen.push(ListenBlock {
+            address,
+            ..ListenBlock::default()
+        });
+        if config.tls.is_some() {
+            config.listen.push(ListenBlock {
+                address,
+                tls: true,
+                ..ListenBlock::default()
+            });
+        }
+    }
+
+    let mut server = Server::bind(config).await.unwrap();
+    if let Some(resolver) = resolver {
+        server.connections.resolver = resolver;
+    }
+    let find = |f: fn(&ListenBlock) -> bool| {
+        server
+            .listeners
+            .iter()
+            .map(|listener| &listener.listen)
+            .find(|listen| listen.address.is_some() && f(listen))
+            .and_then(|listen| listen.address)
+    };
+    let addr = find(|listen| !listen.tls && !listen.websocket && listen.clients).unwrap();
+    let tls_addr = find(|listen| listen.tls && !listen.websocket);
+    let websocket_addr = find(|listen| listen.websocket);
+    let connections = server.connections.clone();
+    let acceptor = Acceptor::new(connections.clone(), ListenBlock::default());
+
+    tokio::spawn(async move {
+        let _ = server.start_server().await;
+    });
+
+    ServerInfo {
+        addr,
+        tls_addr,
+        websocket_addr,
+        connections,
+        acceptor,
+    }
+}
+
+// Writes a new self-signed certificate for `localhost` and returns it.
+fn write_certificate(cert: &Path, key: &Path) -> CertificateDer<'static> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    std::fs::write(cert, certified.cert.pem()).unwrap();
+    std::fs::write(key, certified.signing_key.serialize_pem()).unwrap();
+
+    certified.cert.der().clone()
+}
+
+// Presents `identity` as the client certificate when it is given.
+async fn connect_tls(
+    addr: SocketAddr,
+    certificate: CertificateDer<'static>,
+    identity: Option<&rcgen::CertifiedKey<rcgen::KeyPair>>,
+) -> Result<BufReader<TlsStream<TcpStream>>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(certificate)?;
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    let mut config = match identity {
+        Some(identity) => builder.with_client_auth_cert(
+            vec![identity.cert.der().clone()],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(identity.signing_key.serialize_der())),
+        )?,
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"irc".to_vec()];
+
+    let stream = TcpStream::connect(addr).await?;
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
 
-    ServerInfo { addr, connections }
+    Ok(BufReader::new(stream))
 }
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use ring::digest::{digest, SHA256};
+use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::version::{TLS12, TLS13};
+use tokio_rustls::rustls::{
+    DigitallySignedStruct, DistinguishedName, Error as TlsError, ServerConfig, ServerConnection,
+    SignatureScheme,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+#[cfg(test)]
+#[path = "./tls_test.rs"]
+mod tls_test;
+
+// The ALPN protocol id registered for IRC over TLS.
+pub const ALPN_IRC: &[u8] = b"irc";
+// What browsers offer when they open a WebSocket over TLS.
+pub const ALPN_HTTP: &[u8] = b"http/1.1";
+
+/// The acceptors for the TLS listeners. Both present the same certificate,
+/// but a WebSocket listener speaks HTTP first and says so in ALPN.
+#[derive(Clone)]
+pub struct Acceptors {
+    pub irc: TlsAcceptor,
+    pub websocket: TlsAcceptor,
+}
+
+/// Builds the acceptors for the TLS listeners from the PEM certificate
+/// chain and private key in `config`. It is called again on REHASH;
+/// sessions that are already established keep the configuration they
+/// started with.
+pub fn acceptors(config: &TlsConfig) -> Result<Acceptors> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Cannot read certificates from {}", config.cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .with_context(|| format!("Cannot read private key from {}", config.key.display()))?;
+
+    let provider = Arc::new(crypto::ring::default_provider());
+    let mut server_config = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&TLS12, &TLS13])?
+        .with_client_cert_verifier(Arc::new(AnyClientCert { provider }))
+        .with_single_cert(certs, key)?;
+    let mut websocket_config = server_config.clone();
+    server_config.alpn_protocols = vec![ALPN_IRC.to_vec()];
+    websocket_config.alpn_protocols = vec![ALPN_HTTP.to_vec()];
+
+    Ok(Acceptors {
+        irc: TlsAcceptor::from(Arc::new(server_config)),
+        websocket: TlsAcceptor::from(Arc::new(websocket_config)),
+    })
+}
+
+// SHA-256 of the DER certificate, as lowercase hex without separators.
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    digest(&SHA256, cert.as_ref())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn peer_fingerprint(connection: &ServerConnection) -> Option<String> {
+    connection.peer_certificates()?.first().map(fingerprint)
+}
+
+// Fingerprints in the configuration may be written in upper case or with
+// `:` between the bytes.
+pub fn same_fingerprint(a: &str, b: &str) -> bool {
+    let normalize = |s: &str| {
+        s.chars()
+            .filter(|c| *c != ':')
+            .map(|c| c.to_ascii_lowercase())
+            .collect::<String>()
+    };
+
+    normalize(a) == normalize(b)
+}
+
+/// Asks for a client certificate without requiring one. Any certificate is
+/// accepted, self-signed ones included: it identifies the client by its
+/// fingerprint, not by who issued it. The handshake signature is still
+/// checked, so the client has to hold the private key.
+#[derive(Debug)]
+struct AnyClientCert {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, TlsError> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
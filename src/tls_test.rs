@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::PathBuf;
+
+use super::*;
+
+fn write_cert(name: &str) -> TlsConfig {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let dir = std::env::temp_dir();
+    let cert = dir.join(format!("avalon-{}-{}.crt", name, std::process::id()));
+    let key = dir.join(format!("avalon-{}-{}.key", name, std::process::id()));
+
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+
+    TlsConfig {
+        cert,
+        key,
+        port: 6697,
+        sts_duration: None,
+    }
+}
+
+#[test]
+fn test_acceptor() {
+    let config = write_cert("acceptor");
+
+    let acceptors = acceptors(&config).unwrap();
+    assert_eq!(vec![ALPN_IRC.to_vec()], acceptors.irc.config().alpn_protocols);
+    assert_eq!(vec![ALPN_HTTP.to_vec()], acceptors.websocket.config().alpn_protocols);
+
+    fs::remove_file(config.cert).unwrap();
+    fs::remove_file(config.key).unwrap();
+}
+
+#[test]
+fn test_acceptor_errors() {
+    let config = write_cert("errors");
+
+    let missing = TlsConfig {
+        key: PathBuf::from("/nonexistent/avalon.key"),
+        ..config.clone()
+    };
+    assert!(acceptors(&missing).is_err());
+
+    // The certificate is not a private key.
+    let swapped = TlsConfig {
+        key: config.cert.clone(),
+        ..config.clone()
+    };
+    assert!(acceptors(&swapped).is_err());
+
+    fs::remove_file(config.cert).unwrap();
+    fs::remove_file(config.key).unwrap();
+}
+
+#[test]
+fn test_fingerprint() {
+    let cert = CertificateDer::from(&b"not really a certificate"[..]);
+
+    assert_eq!(
+        "d6182255e5739d55fc9781759c7a823d4dff3d2b7d17949e7085ee7c0cc22acf",
+        fingerprint(&cert)
+    );
+}
+
+#[test]
+fn test_same_fingerprint() {
+    assert!(same_fingerprint("ab:cd:EF", "abcdef"));
+    assert!(same_fingerprint("ABCDEF", "abcdef"));
+    assert!(!same_fingerprint("abcdee", "abcdef"));
+}
@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+
+/// How a client reached the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Tls,
+    WebSocket,
+    Unix,
+}
+
+/// What is known about the other end of a connection before it sends
+/// anything. It goes along with the stream into the client pipeline.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    // A WebSocket may or may not be running over TLS.
+    pub secure: bool,
+    // Fingerprint of the client certificate, when one was presented.
+    pub certfp: Option<String>,
+    // Our end of a TCP connection, which ident queries need.
+    pub local: Option<SocketAddr>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr, transport: Transport) -> Peer {
+        Peer {
+            addr,
+            transport,
+            // Local sockets never cross the network.
+            secure: matches!(transport, Transport::Tls | Transport::Unix),
+            certfp: None,
+            local: None,
+        }
+    }
+}
@@ -1,8 +1,31 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
+
+use crate::messages::{format_mode_changes, parse_mode_changes};
+
+#[cfg(test)]
+#[path = "./user_test.rs"]
+mod user_test;
+
+pub const USER_MODES: &str = "Biorsw";
+
 pub struct User {
     pub nick: Option<String>,
     pub user: Option<String>,
     pub host: Option<String>,
     pub full_name: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub caps: HashSet<String>,
+    pub away: Option<String>,
+    pub modes: HashSet<char>,
+    pub privileges: HashSet<String>,
+    pub account: Option<String>,
+    pub secure: bool,
+    // SHA-256 fingerprint of the TLS client certificate.
+    pub certfp: Option<String>,
+    pub signon: SystemTime,
+    pub last_active: Instant,
 }
 
 impl User {
@@ -12,6 +35,76 @@ impl User {
             user: None,
             host: None,
             full_name: None,
+            ip: None,
+            caps: HashSet::new(),
+            away: None,
+            modes: HashSet::new(),
+            privileges: HashSet::new(),
+            account: None,
+            secure: false,
+            certfp: None,
+            signon: SystemTime::now(),
+            last_active: Instant::now(),
         }
     }
+
+    // What hostmasks show and bans match, set when the connection is
+    // registered.
+    pub fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or("*")
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.contains(cap)
+    }
+
+    pub fn is_oper(&self) -> bool {
+        self.modes.contains(&'o')
+    }
+
+    pub fn has_privilege(&self, privilege: &str) -> bool {
+        self.is_oper() && self.privileges.contains(privilege)
+    }
+
+    pub fn is_invisible(&self) -> bool {
+        self.modes.contains(&'i')
+    }
+
+    pub fn mode_string(&self) -> String {
+        let mut modes = self.modes.iter().collect::<Vec<_>>();
+        modes.sort();
+
+        let mut result = String::from("+");
+        result.extend(modes);
+        result
+    }
+
+    // Applies a MODE request from the user themselves. +o can only be
+    // dropped and +r is only ever set by the server, so both are ignored
+    // otherwise. Returns the applied changes and whether any flag was unknown.
+    pub fn apply_modes(&mut self, modes: &str) -> (String, bool) {
+        let mut applied = vec![];
+        let mut unknown = false;
+
+        for (adding, c) in parse_mode_changes(modes) {
+            if !USER_MODES.contains(c) {
+                unknown = true;
+                continue;
+            }
+            if c == 'r' || (c == 'o' && adding) {
+                continue;
+            }
+
+            let changed = if adding {
+                self.modes.insert(c)
+            } else {
+                self.modes.remove(&c)
+            };
+            if changed {
+                applied.push((adding, c));
+            }
+        }
+
+        (format_mode_changes(&applied), unknown)
+    }
 }
@@ -0,0 +1,23 @@
+use super::*;
+
+#[test]
+fn test_apply_user_modes() {
+    let mut user = User::new();
+
+    let (applied, unknown) = user.apply_modes("+iwxB");
+    assert_eq!("+iwB", applied);
+    assert!(unknown);
+    assert_eq!("+Biw", user.mode_string());
+
+    let (applied, unknown) = user.apply_modes("+or");
+    assert_eq!("", applied);
+    assert!(!unknown);
+    assert!(!user.is_oper());
+
+    user.modes.insert('o');
+    user.modes.insert('r');
+    let (applied, _) = user.apply_modes("-or-i");
+    assert_eq!("-oi", applied);
+    assert!(!user.is_oper());
+    assert!(user.modes.contains(&'r'));
+}
@@ -0,0 +1,216 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::mask;
+
+#[cfg(test)]
+#[path = "./websocket_test.rs"]
+mod websocket_test;
+
+// The subprotocols from the IRCv3 WebSocket spec.
+pub const TEXT_PROTOCOL: &str = "text.ircv3.net";
+pub const BINARY_PROTOCOL: &str = "binary.ircv3.net";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Text,
+    Binary,
+}
+
+impl Mode {
+    // The first subprotocol the client offers that we speak, if any.
+    fn negotiate(offered: &str) -> Option<Mode> {
+        offered.split(',').map(str::trim).find_map(|protocol| match protocol {
+            TEXT_PROTOCOL => Some(Mode::Text),
+            BINARY_PROTOCOL => Some(Mode::Binary),
+            _ => None,
+        })
+    }
+
+    fn protocol(&self) -> &'static str {
+        match self {
+            Mode::Text => TEXT_PROTOCOL,
+            Mode::Binary => BINARY_PROTOCOL,
+        }
+    }
+}
+
+// An empty allow-list lets any page connect, as well as clients that do not
+// send an Origin at all.
+pub fn origin_allowed(origins: &[String], origin: Option<&str>) -> bool {
+    if origins.is_empty() {
+        return true;
+    }
+
+    origin.is_some_and(|origin| origins.iter().any(|allowed| mask::matches(allowed, origin)))
+}
+
+/// Runs the WebSocket handshake on `stream`. The Origin header is checked
+/// against `origins` and the IRCv3 subprotocol is negotiated; clients that
+/// do not ask for one get text frames.
+pub async fn accept<S>(stream: S, origins: &[String]) -> io::Result<WebSocketLines<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut mode = Mode::Text;
+
+    // The signature is the one tungstenite asks for.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+        let origin = request.headers().get("Origin").and_then(|v| v.to_str().ok());
+        if !origin_allowed(origins, origin) {
+            let mut error = ErrorResponse::new(Some("Origin not allowed".into()));
+            *error.status_mut() = StatusCode::FORBIDDEN;
+            return Err(error);
+        }
+
+        let offered = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(Mode::negotiate);
+        if let Some(offered) = offered {
+            mode = offered;
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(offered.protocol()));
+        }
+
+        Ok(response)
+    };
+
+    let stream = tokio_tungstenite::accept_hdr_async(stream, callback)
+        .await
+        .map_err(io::Error::other)?;
+
+    Ok(WebSocketLines::new(stream, mode))
+}
+
+/// Presents a WebSocket as the line-based byte stream the rest of the
+/// server reads and writes. Each incoming text or binary message is one IRC
+/// line and gets its `\r\n` back; each line written is sent as one message,
+/// without the terminator.
+pub struct WebSocketLines<S> {
+    stream: WebSocketStream<S>,
+    mode: Mode,
+    // Bytes of received messages the reader has not taken yet.
+    incoming: Vec<u8>,
+    // Written bytes that are not a complete line yet, or complete lines
+    // waiting for the socket.
+    outgoing: Vec<u8>,
+}
+
+impl<S> WebSocketLines<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: WebSocketStream<S>, mode: Mode) -> Self {
+        WebSocketLines {
+            stream,
+            mode,
+            incoming: vec![],
+            outgoing: vec![],
+        }
+    }
+
+    // Hands complete lines from `outgoing` to the socket, one message each.
+    fn poll_send_lines(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(end) = self.outgoing.iter().position(|b| *b == b'\n') {
+            ready!(Pin::new(&mut self.stream).poll_ready(cx)).map_err(io::Error::other)?;
+
+            let mut line = self.outgoing.drain(..=end).collect::<Vec<_>>();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            let message = match self.mode {
+                Mode::Text => Message::text(
+                    String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                ),
+                Mode::Binary => Message::binary(line),
+            };
+            Pin::new(&mut self.stream)
+                .start_send(message)
+                .map_err(io::Error::other)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for WebSocketLines<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.incoming.is_empty() {
+            let line = match ready!(Pin::new(&mut this.stream).poll_next(cx)) {
+                Some(Ok(Message::Text(text))) => text.as_bytes().to_vec(),
+                Some(Ok(Message::Binary(data))) => data.to_vec(),
+                // Pings are answered by tungstenite itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            };
+
+            this.incoming = line;
+            if !this.incoming.ends_with(b"\n") {
+                this.incoming.extend_from_slice(b"\r\n");
+            }
+        }
+
+        let n = buf.remaining().min(this.incoming.len());
+        buf.put_slice(&this.incoming[..n]);
+        this.incoming.drain(..n);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WebSocketLines<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Lines from earlier writes go first, so the buffer stays bounded
+        // by what the socket takes.
+        ready!(this.poll_send_lines(cx))?;
+        this.outgoing.extend_from_slice(buf);
+        if let Poll::Ready(Err(e)) = this.poll_send_lines(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.stream).poll_close(cx).map_err(io::Error::other)
+    }
+}
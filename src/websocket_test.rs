@@ -0,0 +1,78 @@
+use futures::{SinkExt, StreamExt};
+use tokio::io::{duplex, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+use super::*;
+
+#[test]
+fn test_negotiate() {
+    assert_eq!(Some(Mode::Text), Mode::negotiate("text.ircv3.net"));
+    assert_eq!(
+        Some(Mode::Binary),
+        Mode::negotiate("chat, binary.ircv3.net, text.ircv3.net")
+    );
+    assert_eq!(None, Mode::negotiate("chat"));
+}
+
+#[test]
+fn test_origin_allowed() {
+    assert!(origin_allowed(&[], None));
+    assert!(origin_allowed(&[], Some("https://evil.example.org")));
+
+    let origins = vec!["https://web.example.com".into(), "https://*.example.net".into()];
+    assert!(origin_allowed(&origins, Some("https://web.example.com")));
+    assert!(origin_allowed(&origins, Some("https://chat.example.net")));
+    assert!(!origin_allowed(&origins, Some("https://evil.example.org")));
+    assert!(!origin_allowed(&origins, None));
+}
+
+#[tokio::test]
+async fn test_lines() {
+    let (client, server) = duplex(4096);
+    let server = tokio::spawn(async move { accept(server, &[]).await.unwrap() });
+
+    let mut request = "ws://localhost/".into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(BINARY_PROTOCOL));
+    let (mut client, response) = tokio_tungstenite::client_async(request, client).await.unwrap();
+    assert_eq!(
+        Some(BINARY_PROTOCOL),
+        response.headers().get("Sec-WebSocket-Protocol").and_then(|v| v.to_str().ok())
+    );
+    let mut server = BufReader::new(server.await.unwrap());
+
+    client.send(Message::binary(&b"NICK bob"[..])).await.unwrap();
+    client.send(Message::text("USER bob 0 * :Bob")).await.unwrap();
+    let mut line = String::new();
+    server.read_line(&mut line).await.unwrap();
+    assert_eq!("NICK bob\r\n", line);
+    line.clear();
+    server.read_line(&mut line).await.unwrap();
+    assert_eq!("USER bob 0 * :Bob\r\n", line);
+
+    // Writes are sent a line at a time, whatever way they are split.
+    server.write_all(b"PING :a\r\nPI").await.unwrap();
+    server.write_all(b"NG :b\r\n").await.unwrap();
+    server.flush().await.unwrap();
+    assert_eq!(Message::binary(&b"PING :a"[..]), client.next().await.unwrap().unwrap());
+    assert_eq!(Message::binary(&b"PING :b"[..]), client.next().await.unwrap().unwrap());
+
+    client.close(None).await.unwrap();
+    line.clear();
+    assert_eq!(0, server.read_line(&mut line).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_origin_rejected() {
+    let (client, server) = duplex(4096);
+    let origins = vec!["https://web.example.com".to_string()];
+    let server = tokio::spawn(async move { accept(server, &origins).await.is_err() });
+
+    let mut request = "ws://localhost/".into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Origin", HeaderValue::from_static("https://evil.example.org"));
+    assert!(tokio_tungstenite::client_async(request, client).await.is_err());
+    assert!(server.await.unwrap());
+}
@@ -0,0 +1,49 @@
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+#[cfg(test)]
+#[path = "./whowas_test.rs"]
+mod whowas_test;
+
+pub const WHOWAS_MAX_ENTRIES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhowasEntry {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub full_name: String,
+    pub time: SystemTime,
+}
+
+#[derive(Debug)]
+pub struct WhowasHistory {
+    entries: VecDeque<WhowasEntry>,
+    capacity: usize,
+}
+
+impl WhowasHistory {
+    pub fn new(capacity: usize) -> Self {
+        WhowasHistory {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, entry: WhowasEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    // Most recent entries first, as WHOWAS replies expect.
+    pub fn lookup(&self, nick: &str, count: Option<usize>) -> Vec<&WhowasEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.nick == nick)
+            .take(count.filter(|c| *c > 0).unwrap_or(usize::MAX))
+            .collect()
+    }
+}
@@ -0,0 +1,44 @@
+use super::*;
+
+fn entry(nick: &str, user: &str) -> WhowasEntry {
+    WhowasEntry {
+        nick: nick.into(),
+        user: user.into(),
+        host: "host".into(),
+        full_name: "Full Name".into(),
+        time: SystemTime::now(),
+    }
+}
+
+#[test]
+fn test_lookup_most_recent_first() {
+    let mut history = WhowasHistory::new(10);
+
+    history.record(entry("bob", "first"));
+    history.record(entry("ana", "ana"));
+    history.record(entry("bob", "second"));
+
+    let users = history
+        .lookup("bob", None)
+        .iter()
+        .map(|e| e.user.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(vec!["second", "first"], users);
+
+    assert_eq!(1, history.lookup("bob", Some(1)).len());
+    assert_eq!(2, history.lookup("bob", Some(0)).len());
+    assert!(history.lookup("joe", None).is_empty());
+}
+
+#[test]
+fn test_history_is_bounded() {
+    let mut history = WhowasHistory::new(2);
+
+    history.record(entry("bob", "bob"));
+    history.record(entry("ana", "ana"));
+    history.record(entry("joe", "joe"));
+
+    assert!(history.lookup("bob", None).is_empty());
+    assert_eq!(1, history.lookup("ana", None).len());
+    assert_eq!(1, history.lookup("joe", None).len());
+}